@@ -0,0 +1,12 @@
+// action.rs
+// Handles editing actions -- the mutations that can be applied to the open document
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Action {
+    Insert(String), // Insert a run of characters at the cursor
+    Newline,        // Split the current line at the cursor
+    Backspace,      // Delete the character before the cursor (joining lines at column 0)
+    Delete,         // Delete the character under the cursor (joining the next line at the end of a line)
+    ToggleCase,     // Toggle the case of the character under the cursor and advance past it
+    Align(String),  // Line up the first occurrence of a delimiter across the paragraph at the cursor
+}
@@ -0,0 +1,87 @@
+// bench.rs
+// Handles the --bench-open mode -- timing loading, scrolling and editing a file without a terminal
+
+use std::time::{Duration, Instant};
+
+use termion::event::Key;
+
+use crate::editor::Editor;
+use crate::terminal::{HeadlessBackend, Terminal};
+use crate::{Action, Config, Document};
+
+const EDITS: usize = 1000; // Actions applied in the edit benchmark
+
+// Latencies collected for one operation
+struct Timings {
+    name: &'static str,      // Operation measured
+    samples: Vec<Duration>,  // How long each run took
+}
+
+impl Timings {
+    fn new(name: &'static str) -> Self {
+        Self { name, samples: vec![] }
+    }
+
+    // Runs operation once, recording how long it took
+    fn time<T>(&mut self, operation: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = operation();
+        self.samples.push(start.elapsed());
+        result
+    }
+
+    // One line of the report -- runs, total, mean, median and worst latency
+    fn report(&mut self) -> String {
+        self.samples.sort_unstable();
+        let total: Duration = self.samples.iter().sum();
+        let count = self.samples.len().max(1) as u32;
+        format!(
+            "{:<12} {:>8} {:>12.3?} {:>12.3?} {:>12.3?} {:>12.3?}",
+            self.name,
+            self.samples.len(),
+            total,
+            total / count,
+            self.samples.get(self.samples.len() / 2).copied().unwrap_or_default(),
+            self.samples.last().copied().unwrap_or_default()
+        )
+    }
+}
+
+// Loads path, scrolls through all of it and makes a batch of edits on an 80x24 headless editor, then prints the timings
+// Nothing is saved
+pub fn run(path: &str) {
+    let mut load = Timings::new("load");
+    let document = load.time(|| Document::from_file(path)).expect("Could not read the file");
+    let line_count = document.lines.len();
+    drop(document);
+
+    let terminal = Terminal::new(Box::new(HeadlessBackend { width: 80, height: 24 }));
+    let mut editor = Editor::new(terminal, Config::default());
+    let mut open = Timings::new("open");
+    open.time(|| editor.open_file(path));
+
+    // Scroll to the end a line at a time, drawing every frame
+    let (mut scroll, mut draw) = (Timings::new("scroll key"), Timings::new("draw"));
+    for _ in 1..line_count {
+        scroll.time(|| editor.handle_key(Key::Down)).expect("Could not move the cursor");
+        draw.time(|| editor.draw()).expect("Could not draw");
+    }
+
+    // Type, split and delete through the action enum
+    let mut edit = Timings::new("edit action");
+    for index in 0..EDITS {
+        let action = match index % 4 {
+            0 => Action::Insert("bench".to_string()),
+            1 => Action::Newline,
+            2 => Action::Backspace,
+            _ => Action::Insert("x".to_string()),
+        };
+        edit.time(|| editor.apply_action(&action));
+    }
+
+    println!("{} ({} lines)", path, line_count);
+    println!("{:<12} {:>8} {:>12} {:>12} {:>12} {:>12}", "operation", "runs", "total", "mean", "median", "worst");
+    for timings in [&mut load, &mut open, &mut scroll, &mut draw, &mut edit] {
+        println!("{}", timings.report());
+    }
+}
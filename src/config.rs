@@ -0,0 +1,215 @@
+// config.rs
+// Handles editor configuration -- default settings and loading overrides from ~/.bimrc
+
+use crate::terminal::Color;
+
+pub struct Config {
+    pub auto_save_on_focus_lost: bool,       // Save when the terminal loses focus (needs focus reporting support)
+    pub status_format: String,               // Status bar template, see Editor::format_status for placeholders
+    pub large_file_threshold: u64,           // Files bigger than this many bytes get a warning when opened
+    pub lazy_load_threshold: u64,            // Files bigger than this many bytes only have the lines around the view read until edited
+    pub create_parent_dirs: bool,            // Create missing directories when saving to a new path
+    pub highlight_trailing_whitespace: bool, // Paint trailing spaces and tabs red while editing
+    pub highlight_word: bool,                // Paint every visible occurrence of the word under the cursor
+    pub syntax_highlighting: bool,           // Color keywords, strings, numbers and comments
+    pub backup_count: usize,                 // How many numbered backups (file.1, file.2, ...) to keep on save, 0 for none
+    pub redraw_throttle_ms: u64,             // Wait this long for more keys before redrawing, so bursts draw once (0 redraws every key)
+    pub coalesce_movement: bool,             // Apply all movement keys already waiting before redrawing, so held arrows don't lag
+    pub shell: String,                       // Shell used to run commands from the editor
+    pub continue_comments: bool,             // Enter inside a comment starts the new line with the comment prefix (code files only)
+    pub auto_indent: bool,                   // Enter starts the new line with the indentation of the line it splits
+    pub scroll_jump: usize,                  // Lines to scroll when Enter moves past the bottom row
+    pub tab_width: usize,                    // Columns between tab stops, tabs are drawn up to the next one
+    pub display_tab_width: usize,            // Tab stops used instead while the document is read-only, 0 to keep tab_width
+    pub expand_tabs: bool,                   // Should Tab insert spaces up to the next tab stop instead of a tab?
+    pub extend_past_end: bool,               // Down on the last line adds an empty line instead of stopping
+    pub idle_quit_seconds: u64,              // Save and quit after this many seconds without a key, 0 to never
+    pub status_clock: bool,                  // Show a clock at the right of the status bar, redrawn every second
+    pub scratch_file: String,                // File opened when bim is started without one, empty for none
+    pub start_page_without_file: bool,       // Start on the start page with an untitled buffer when no file is given, rather than exiting
+    pub empty_line_marker: String,           // Drawn on rows past the end of the document, empty to leave them blank
+    pub empty_line_color: Color,             // Color of the empty line marker
+    pub trim_blank_lines_on_save: bool,      // Drop blank lines at the end of the file and end it with one newline when saving
+    pub ensure_trailing_newline: bool,       // End every non-empty file with a newline when saving, even if it was loaded without one
+    pub trim_whitespace_on_save: bool,       // Strip trailing spaces and tabs from each line written when saving
+    pub trim_whitespace_in_buffer: bool,     // Strip them from the open document too when trim_whitespace_on_save is on, not just the file
+    pub markdown_preview: bool,              // Write an ANSI rendering of .md files to file.md.preview on save
+    pub vim_mode: bool,                      // Vim-like normal and insert modes instead of always typing text
+    pub lock_files: String,                  // Comma separated sibling names that mean another editor has the file open, {name} is the file name
+    pub read_only_when_locked: bool,         // Open a file read-only when one of the lock files sits next to it
+    pub search_centers_match: bool,          // Scroll a search match to the middle of the screen unless it is already in the middle third
+    pub confirm_cut_lines: usize,            // Ask before cutting a selection spanning more lines than this, 0 to never ask
+    pub undo_limit: usize,                   // How many changes (each run of typing counting as one) can be undone
+    pub undo_coalesce_ms: u64,               // A pause in typing longer than this starts a new undo step, 0 to never split a run
+    pub errors: Vec<String>,                 // Problems with lines of ~/.bimrc, which were skipped, to tell the user about
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            auto_save_on_focus_lost: false,
+            status_format: "{buffers}Ln {line}, Col {col} / {total} lines{modified}{readonly} ESC for Options ".to_string(),
+            large_file_threshold: 50 * 1024 * 1024,
+            lazy_load_threshold: 100 * 1024 * 1024,
+            create_parent_dirs: true,
+            highlight_trailing_whitespace: false,
+            highlight_word: false,
+            syntax_highlighting: true,
+            backup_count: 0,
+            redraw_throttle_ms: 0,
+            coalesce_movement: true,
+            shell: std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()),
+            continue_comments: false,
+            auto_indent: false,
+            scroll_jump: 1,
+            tab_width: 8,
+            display_tab_width: 0,
+            expand_tabs: false,
+            extend_past_end: false,
+            idle_quit_seconds: 0,
+            status_clock: false,
+            scratch_file: "".to_string(),
+            start_page_without_file: true,
+            empty_line_marker: "~".to_string(),
+            empty_line_color: Color::Reset,
+            trim_blank_lines_on_save: false,
+            ensure_trailing_newline: false,
+            trim_whitespace_on_save: false,
+            trim_whitespace_in_buffer: false,
+            markdown_preview: false,
+            vim_mode: false,
+            lock_files: ".{name}.swp,.#{name}".to_string(),
+            read_only_when_locked: false,
+            search_centers_match: false,
+            confirm_cut_lines: 100,
+            undo_limit: 1000,
+            undo_coalesce_ms: 1000,
+            errors: vec![],
+        }
+    }
+}
+
+impl Config {
+    // Loads ~/.bimrc over the defaults, ignoring the file if it doesn't exist
+    // Each line is a `key = value` setting, blank lines and lines starting with # are skipped
+    pub fn load() -> Self {
+        let mut config = Self::default();
+        let home = match std::env::var("HOME") {
+            Ok(home) => home,
+            Err(_) => return config, // Nowhere to look for a config file
+        };
+        if let Ok(file) = std::fs::read_to_string(std::path::Path::new(&home).join(".bimrc")) {
+            config.apply(&file);
+        }
+        config
+    }
+
+    // Applies each `key = value` line of text in turn
+    // Unknown keys and bad values are skipped and noted in errors, so a stale config never stops bim from starting
+    pub fn apply(&mut self, text: &str) {
+        for (number, line) in text.lines().enumerate() { // Iterate over settings
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { // Skip blanks and comments
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => Err(format!("Expected key = value, got {}", line)),
+            };
+            if let Err(error) = result {
+                self.errors.push(format!("~/.bimrc line {}: {}", number + 1, error));
+            }
+        }
+    }
+
+    // Applies a single setting by name
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "auto_save_on_focus_lost" => self.auto_save_on_focus_lost = parse_bool(value)?,
+            "status_format" => self.status_format = value.to_string(),
+            "large_file_threshold" => self.large_file_threshold = parse_number(value)?,
+            "lazy_load_threshold" => self.lazy_load_threshold = parse_number(value)?,
+            "create_parent_dirs" => self.create_parent_dirs = parse_bool(value)?,
+            "highlight_trailing_whitespace" => self.highlight_trailing_whitespace = parse_bool(value)?,
+            "highlight_word" => self.highlight_word = parse_bool(value)?,
+            "syntax_highlighting" => self.syntax_highlighting = parse_bool(value)?,
+            "backup_count" => self.backup_count = parse_number(value)?,
+            "redraw_throttle_ms" => self.redraw_throttle_ms = parse_number(value)?,
+            "coalesce_movement" => self.coalesce_movement = parse_bool(value)?,
+            "shell" => self.shell = value.to_string(),
+            "continue_comments" => self.continue_comments = parse_bool(value)?,
+            "auto_indent" => self.auto_indent = parse_bool(value)?,
+            "scroll_jump" => self.scroll_jump = parse_number(value)?,
+            "tab_width" => self.tab_width = parse_number::<usize>(value)?.max(1),
+            "display_tab_width" => self.display_tab_width = parse_number(value)?,
+            "expand_tabs" => self.expand_tabs = parse_bool(value)?,
+            "extend_past_end" => self.extend_past_end = parse_bool(value)?,
+            "idle_quit_seconds" => self.idle_quit_seconds = parse_number(value)?,
+            "status_clock" => self.status_clock = parse_bool(value)?,
+            "scratch_file" => self.scratch_file = value.to_string(),
+            "start_page_without_file" => self.start_page_without_file = parse_bool(value)?,
+            "empty_line_marker" => self.empty_line_marker = value.to_string(),
+            "empty_line_color" => self.empty_line_color = parse_color(value)?,
+            "trim_blank_lines_on_save" => self.trim_blank_lines_on_save = parse_bool(value)?,
+            "ensure_trailing_newline" => self.ensure_trailing_newline = parse_bool(value)?,
+            "trim_whitespace_on_save" => self.trim_whitespace_on_save = parse_bool(value)?,
+            "trim_whitespace_in_buffer" => self.trim_whitespace_in_buffer = parse_bool(value)?,
+            "markdown_preview" => self.markdown_preview = parse_bool(value)?,
+            "vim_mode" => self.vim_mode = parse_bool(value)?,
+            "lock_files" => self.lock_files = value.to_string(),
+            "read_only_when_locked" => self.read_only_when_locked = parse_bool(value)?,
+            "search_centers_match" => self.search_centers_match = parse_bool(value)?,
+            "confirm_cut_lines" => self.confirm_cut_lines = parse_number(value)?,
+            "undo_limit" => self.undo_limit = parse_number::<usize>(value)?.max(1),
+            "undo_coalesce_ms" => self.undo_coalesce_ms = parse_number(value)?,
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Parses a boolean setting value
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(format!("Expected true or false, got {}", value)),
+    }
+}
+
+// Parses a whole number setting value
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Expected a number, got {}", value))
+}
+
+// Parses a color setting value
+fn parse_color(value: &str) -> Result<Color, String> {
+    match value {
+        "default" => Ok(Color::Reset),
+        "white" => Ok(Color::White),
+        "red" => Ok(Color::Red),
+        "green" => Ok(Color::Green),
+        "yellow" => Ok(Color::Yellow),
+        "blue" => Ok(Color::Blue),
+        "magenta" => Ok(Color::Magenta),
+        "cyan" => Ok(Color::Cyan),
+        _ => Err(format!("Expected default, white, red, green, yellow, blue, magenta or cyan, got {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_lines_are_skipped_and_reported() {
+        let mut config = Config::default();
+        config.apply("# settings\ntab_width = 4\nempty_line_color = purple\nno equals sign\nwrap = yes\n");
+        assert_eq!(config.tab_width, 4);
+        assert_eq!(config.errors, vec![
+            "~/.bimrc line 3: Expected default, white, red, green, yellow, blue, magenta or cyan, got purple",
+            "~/.bimrc line 4: Expected key = value, got no equals sign",
+            "~/.bimrc line 5: Unknown setting wrap",
+        ]);
+    }
+}
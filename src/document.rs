@@ -1,51 +1,864 @@
 // document.rs
 // Handles document instance and utils -- importing a file to a Document, saving a Document to a file
 
-use std::io::Write;
+use std::io::{BufRead, Read, Seek, Write};
+use std::ops::Range;
+
+const LAZY_MARGIN: usize = 1000; // Lines read either side of those asked for in a lazily loaded document, so scrolling rarely reads
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineChange {
+    Unchanged, // Same as when the document was loaded
+    Modified,  // Replaces a line that was there when loaded or last saved
+    Added,     // New since the document was loaded or last saved
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,   // \n (Unix)
+    CrLf, // \r\n (Windows)
+    Cr,   // \r (classic Mac)
+}
+
+impl LineEnding {
+    // The characters written between lines
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    // Short name for the status bar
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "unix",
+            LineEnding::CrLf => "dos",
+            LineEnding::Cr => "mac",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileKind {
+    Missing,                // Nothing there yet, opening creates it on save
+    Regular,                // An ordinary file
+    Directory,              // A directory, to pick a file from
+    Special(&'static str),  // Anything else (device, socket, fifo), never opened
+}
+
+// Where each line of a lazily loaded file starts, so lines can be read from it as they're needed
+pub struct LazyLines {
+    line_starts: Vec<u64>, // Byte offset of the start of each line
+    size: u64,             // Length of the file in bytes
+    loaded: Range<usize>,  // Lines read into the document, the others are empty placeholders
+}
 
 pub struct Document {
-    pub lines: Vec<String>, // Lines of text
-    pub path: String,       // Path to file
+    pub lines: Vec<String>,          // Lines of text, never containing \n or \r (line breaks are between entries)
+    pub path: String,                // Path to file
+    pub trailing_newline: bool,      // Does the file end with a newline?
+    pub new_file: bool,              // Not read from a file, so it ends with a newline once it has any text
+    pub line_ending: LineEnding,     // Line ending written between lines on save
+    pub original_lines: Vec<String>, // Lines as they were when loaded or last saved, for comparing against
+    pub lazy: Option<LazyLines>,     // Some while only some of the lines have been read from the file
 }
 
-impl Document {
-    pub fn default() -> Self {
+impl Default for Document {
+    fn default() -> Self {
         Self {
             lines: vec!["".to_string()], // There must be at least one line
             path: "".to_string(),
+            trailing_newline: false,
+            new_file: true,
+            line_ending: LineEnding::Lf,
+            original_lines: vec!["".to_string()],
+            lazy: None,
         }
     }
+}
 
-    // Import file to Document
-    pub fn from_file(path: &str) -> Self {
-        let mut lines = vec![]; // Lines of text
+// Byte offset of character column in line, None past the end of the line (the end itself is a valid column)
+pub fn byte_index(line: &str, column: usize) -> Option<usize> {
+    line.char_indices().map(|(index, _)| index).chain(std::iter::once(line.len())).nth(column)
+}
+
+impl Document {
+    // Import file to Document, an empty Document if there's no file at path yet
+    pub fn from_file(path: &str) -> Result<Self, std::io::Error> {
+        let mut document = Self::default();
         // If file already exists, read it
         if std::path::Path::new(path).exists() {
-            let file = std::fs::read_to_string(path).expect(&format!("Could not read file {}", path));
-            for line in file.lines() { // Iterate over lines
-                lines.push(line.to_string()); // Add file line to lines vector
+            let bytes = std::fs::read(path)?;
+            let file = match String::from_utf8(bytes) {
+                Ok(file) if !file.contains('\0') => file,
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "binary file")), // Not text, or text bim can't show
+            };
+            document = Self::from_text(&file);
+        }
+        document.path = path.to_string();
+        Ok(document)
+    }
+
+    // Import file to Document reading only where its lines start, the text of each line is read by load_lines when it's needed
+    // Opening is quick and memory grows with the number of lines rather than the size of the file, but anything that needs
+    // every line -- editing, searching, saving -- has to materialize the document first, which reads the whole file after all
+    // Lines are split on \n (with a \r before it dropped) and invalid UTF-8 is shown replaced rather than refused
+    pub fn from_file_lazy(path: &str) -> Result<Self, std::io::Error> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut line_starts = vec![0];
+        let mut size = 0; // Bytes read so far
+        let mut crlf = None; // Does the first line end with \r\n?
+        let mut previous = 0; // Byte before the one being looked at
+        loop {
+            let chunk = file.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            for (index, byte) in chunk.iter().enumerate() {
+                match byte {
+                    b'\0' => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "binary file")),
+                    b'\n' => {
+                        crlf.get_or_insert(previous == b'\r');
+                        line_starts.push(size + index as u64 + 1);
+                    }
+                    _ => (),
+                }
+                previous = *byte;
+            }
+            let length = chunk.len();
+            file.consume(length);
+            size += length as u64;
+        }
+        let Some(crlf) = crlf else { // One line, or classic Mac line endings, so there's nothing to gain
+            return Self::from_file(path);
+        };
+        let trailing_newline = line_starts.last() == Some(&size);
+        if trailing_newline { // Nothing starts after the last newline
+            line_starts.pop();
+        }
+        Ok(Self {
+            lines: vec![String::new(); line_starts.len()],
+            path: path.to_string(),
+            trailing_newline,
+            new_file: false,
+            line_ending: if crlf { LineEnding::CrLf } else { LineEnding::Lf },
+            original_lines: vec![],
+            lazy: Some(LazyLines { line_starts, size, loaded: 0..0 }),
+        })
+    }
+
+    // Is only some of the document read from its file?
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    // Has line index been read from the file? Always true for a document that isn't lazily loaded
+    pub fn is_loaded(&self, index: usize) -> bool {
+        self.lazy.as_ref().is_none_or(|lazy| lazy.loaded.contains(&index))
+    }
+
+    // Reads lines range (and LAZY_MARGIN lines either side) of a lazily loaded document from its file,
+    // emptying the lines read last time so memory stays bounded
+    // Does nothing if they're already read or the document isn't lazily loaded
+    pub fn load_lines(&mut self, range: Range<usize>) -> Result<(), std::io::Error> {
+        let Some(lazy) = &mut self.lazy else {
+            return Ok(());
+        };
+        let count = self.lines.len();
+        if lazy.loaded.start <= range.start && range.end.min(count) <= lazy.loaded.end {
+            return Ok(());
+        }
+        for line in &mut self.lines[lazy.loaded.clone()] {
+            *line = String::new(); // Frees the text
+        }
+        lazy.loaded = 0..0;
+        let start = range.start.saturating_sub(LAZY_MARGIN).min(count);
+        let end = range.end.saturating_add(LAZY_MARGIN).min(count);
+        let from = lazy.line_starts[start];
+        let to = lazy.line_starts.get(end).copied().unwrap_or(lazy.size);
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(std::io::SeekFrom::Start(from))?;
+        let mut bytes = vec![0; (to - from) as usize];
+        file.read_exact(&mut bytes)?;
+        for (line, text) in self.lines[start..end].iter_mut().zip(bytes.split(|byte| *byte == b'\n')) {
+            *line = String::from_utf8_lossy(text.strip_suffix(b"\r").unwrap_or(text)).into_owned();
+        }
+        lazy.loaded = start..end;
+        Ok(())
+    }
+
+    // Reads all of a lazily loaded document, after which it is like any other
+    pub fn materialize(&mut self) -> Result<(), std::io::Error> {
+        if self.is_lazy() {
+            *self = Self::from_file(&self.path.clone())?;
+        }
+        Ok(())
+    }
+
+    // Build a Document (with no path) from text
+    // Lines are split on \n, \r\n and lone \r, so stray carriage returns never end up inside a line
+    pub fn from_text(text: &str) -> Self {
+        let mut lines = vec![]; // Lines of text
+        let mut line = String::new(); // Line being read
+        let (mut lf_count, mut crlf_count) = (0, 0); // How often each ending is used, to keep the more common one
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() { // Iterate over characters
+            match c {
+                '\r' => {
+                    if chars.peek() == Some(&'\n') { // \r\n is a single line ending
+                        chars.next();
+                        crlf_count += 1;
+                    }
+                    lines.push(std::mem::take(&mut line));
+                }
+                '\n' => {
+                    lf_count += 1;
+                    lines.push(std::mem::take(&mut line));
+                }
+                _ => line.push(c),
             }
         }
-        if lines.len() == 0 { // Make sure lines vector is not empty
+        if !line.is_empty() { // Last line had no line ending
+            lines.push(line);
+        }
+        if lines.is_empty() { // Make sure lines vector is not empty
             lines.push("".to_string());
         }
+        // Only files with nothing but lone \r line endings are treated as classic Mac, stray \r in other files become
+        // whichever of \n and \r\n is more common (\n when it's a tie, as for new documents)
+        let line_ending = if !text.contains('\n') && text.contains('\r') {
+            LineEnding::Cr
+        } else if crlf_count > lf_count {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        };
         Self {
+            original_lines: lines.clone(),
             lines,
-            path: path.to_string(),
+            path: "".to_string(),
+            trailing_newline: text.ends_with('\n') || text.ends_with('\r'), // Did the text end with a newline?
+            new_file: false,
+            line_ending,
+            lazy: None,
+        }
+    }
+
+    // Convert the Document back to text, exactly as it would be saved
+    pub fn to_text(&self) -> String {
+        self.to_text_trimmed(false)
+    }
+
+    // Same as to_text, with trailing spaces and tabs stripped from each line if trim_whitespace is set
+    pub fn to_text_trimmed(&self, trim_whitespace: bool) -> String {
+        let lines: Vec<&str> = self.lines.iter()
+            .map(|line| if trim_whitespace { line.trim_end_matches([' ', '\t']) } else { line.as_str() })
+            .collect();
+        let mut text = lines.join(self.line_ending.as_str()); // Newlines go between lines
+        // End with a newline if the file had one -- an empty buffer is then a single newline, otherwise nothing
+        if self.ends_with_newline() {
+            text.push_str(self.line_ending.as_str());
+        }
+        text
+    }
+
+    // Line at index, None past the end of the document
+    pub fn line(&self, index: usize) -> Option<&str> {
+        self.lines.get(index).map(|line| line.as_str())
+    }
+
+    // Mutable line at index, None past the end of the document
+    pub fn line_mut(&mut self, index: usize) -> Option<&mut String> {
+        self.lines.get_mut(index)
+    }
+
+    // Length of the line at index in characters (the unit cursor columns are in), 0 past the end of the document
+    pub fn line_len(&self, index: usize) -> usize {
+        self.line(index).map_or(0, |line| line.chars().count())
+    }
+
+    // Inserts c into line index at character column, returning false without changing anything if that's not a valid spot
+    // Line breaks are refused too, lines are split with split_line instead
+    pub fn insert_char(&mut self, index: usize, column: usize, c: char) -> bool {
+        let line = match self.lines.get_mut(index) {
+            Some(line) if c != '\n' && c != '\r' => line,
+            _ => return false,
+        };
+        match byte_index(line, column) {
+            Some(byte) => {
+                line.insert(byte, c);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes the character at character column of line index, None if there isn't one
+    pub fn remove_char(&mut self, index: usize, column: usize) -> Option<char> {
+        let line = self.lines.get_mut(index)?;
+        let byte = byte_index(line, column).filter(|byte| *byte < line.len())?;
+        Some(line.remove(byte))
+    }
+
+    // Splits line index at character column, moving the rest onto a new line after it
+    // Returns false without changing anything if that's not a valid spot
+    pub fn split_line(&mut self, index: usize, column: usize) -> bool {
+        let after = match self.lines.get_mut(index) {
+            Some(line) => match byte_index(line, column) {
+                Some(byte) => line.split_off(byte), // Everything after the column
+                None => return false,
+            },
+            None => return false,
+        };
+        self.lines.insert(index + 1, after);
+        true
+    }
+
+    // Appends line index to the line before it, returning the column they joined at
+    // None for the first line or past the end of the document
+    pub fn join_with_previous(&mut self, index: usize) -> Option<usize> {
+        if index == 0 || index >= self.lines.len() {
+            return None;
+        }
+        let line = self.lines.remove(index);
+        let previous = &mut self.lines[index - 1];
+        let column = previous.chars().count();
+        previous.push_str(&line);
+        Some(column)
+    }
+
+    // Text from (line, column) start up to but not including end, with a \n where it crosses a line break
+    pub fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let lines: Vec<&str> = (start.0..=end.0)
+            .filter_map(|index| {
+                let line = self.lines.get(index)?;
+                let first = if index == start.0 { byte_index(line, start.1).unwrap_or(line.len()) } else { 0 };
+                let last = if index == end.0 { byte_index(line, end.1).unwrap_or(line.len()) } else { line.len() };
+                Some(&line[first..last.max(first)])
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    // Removes the text from (line, column) start up to but not including end, joining the lines either side
+    pub fn remove_between(&mut self, start: (usize, usize), end: (usize, usize)) {
+        if start.0 >= self.lines.len() {
+            return;
+        }
+        let end_line = end.0.min(self.lines.len() - 1);
+        let last = &self.lines[end_line];
+        let tail = match byte_index(last, end.1) { // What's left of the last line after end
+            Some(index) if end.0 == end_line => last[index..].to_string(),
+            _ => String::new(),
+        };
+        let first = &mut self.lines[start.0];
+        first.truncate(byte_index(first, start.1).unwrap_or(first.len()));
+        first.push_str(&tail);
+        self.lines.drain(start.0 + 1..=end_line);
+    }
+
+    // Does the saved file end with a newline? New files do unless they are empty, so an empty buffer saves as zero bytes
+    pub fn ends_with_newline(&self) -> bool {
+        self.trailing_newline || (self.new_file && self.lines != [""])
+    }
+
+    // Number of line endings the saved file contains, which is what wc -l reports
+    // One less than the number of lines when the last line has no newline after it (0 for an empty file)
+    pub fn newline_count(&self) -> usize {
+        self.lines.len() - 1 + self.ends_with_newline() as usize
+    }
+
+    // Strips trailing spaces and tabs from every line, returning whether anything changed
+    pub fn trim_trailing_whitespace(&mut self) -> bool {
+        let mut changed = false;
+        for line in &mut self.lines {
+            let len = line.trim_end_matches([' ', '\t']).len();
+            changed |= len != line.len();
+            line.truncate(len);
+        }
+        changed
+    }
+
+    // Drops blank lines at the end of the document so it ends with exactly one newline, returning whether anything changed
+    // A document with nothing but blank lines becomes empty
+    pub fn trim_trailing_blank_lines(&mut self) -> bool {
+        let keep = self.lines.iter().rposition(|line| !line.trim().is_empty()).map_or(0, |last| last + 1); // Lines up to the last non-blank one
+        let trailing_newline = keep > 0; // An emptied document is saved as zero bytes
+        let changed = keep.max(1) != self.lines.len()
+            || self.ends_with_newline() != trailing_newline
+            || (keep == 0 && !self.lines[0].is_empty());
+        self.lines.truncate(keep.max(1));
+        if keep == 0 { // There must be at least one line
+            self.lines[0].clear();
+        }
+        self.trailing_newline = trailing_newline;
+        changed
+    }
+
+    // Pads lines start..end so the first occurrence of delimiter on each lands in the same column
+    // Lines without the delimiter are left alone
+    pub fn align(&mut self, start: usize, end: usize, delimiter: &str) {
+        if delimiter.is_empty() {
+            return;
+        }
+        let columns: Vec<Option<usize>> = self.lines[start..end].iter() // Column of the delimiter on each line
+            .map(|line| line.find(delimiter).map(|index| line[..index].chars().count()))
+            .collect();
+        let target = match columns.iter().flatten().max() {
+            Some(target) => *target, // Column every delimiter is moved to
+            None => return, // Delimiter doesn't appear anywhere
+        };
+        for (line, column) in self.lines[start..end].iter_mut().zip(columns) {
+            if let Some(column) = column {
+                let index = line.find(delimiter).unwrap(); // Byte index to pad at
+                line.insert_str(index, &" ".repeat(target - column));
+            }
+        }
+    }
+
+    // Compares each line against the lines as loaded
+    // Unchanged lines are matched from the start and end, everything between counts as modified or added
+    pub fn line_changes(&self) -> Vec<LineChange> {
+        if self.is_lazy() { // Never changed, edits read it all first
+            return vec![LineChange::Unchanged; self.lines.len()];
+        }
+        let (lines, original) = (&self.lines, &self.original_lines);
+        let prefix = lines.iter().zip(original).take_while(|(line, old)| line == old).count(); // Unchanged lines at the start
+        let max_suffix = lines.len().min(original.len()) - prefix;
+        let suffix = lines.iter().rev().zip(original.iter().rev()) // Unchanged lines at the end
+            .take(max_suffix)
+            .take_while(|(line, old)| line == old)
+            .count();
+        let replaced = original.len() - prefix - suffix; // Old lines that the changed lines stand in for
+        let mut changes = vec![LineChange::Unchanged; lines.len()];
+        for (offset, change) in changes[prefix..lines.len() - suffix].iter_mut().enumerate() {
+            *change = if offset < replaced { LineChange::Modified } else { LineChange::Added };
+        }
+        changes
+    }
+
+    // Comment syntax for the document's file type -- the line comment marker and whether /* */ blocks are used
+    // None for file types that aren't recognised
+    pub fn comment_syntax(&self) -> Option<(&'static str, bool)> {
+        let extension = std::path::Path::new(&self.path).extension()?.to_str()?;
+        match extension {
+            "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "java" | "js" | "jsx" | "ts" | "tsx" | "go" | "cs" | "kt" | "swift" => Some(("//", true)),
+            "py" | "sh" | "bash" | "rb" | "pl" | "toml" | "yaml" | "yml" | "conf" => Some(("#", false)),
+            _ => None,
         }
     }
 
-    // Save open document to file
-    pub fn save(&self) {
-        let mut output_file = std::fs::File::create(self.path.clone()).expect("Could not create file"); // Create/Open file
+    // Size of the file at path in bytes, or None if it can't be read
+    pub fn file_size(path: &str) -> Option<u64> {
+        std::fs::metadata(path).ok().map(|metadata| metadata.len())
+    }
 
-        let mut first_line_written = false; // Used to determine if a newline should be written
-        for line in self.lines.clone() { // Iterate over lines clone
-            if first_line_written { // If first line has been written, write a newline
-                output_file.write_all("\n".as_bytes()).expect("Could not write to file");
+    // Finds the first occurrence of query at or after character column of line, wrapping round to the start after the last line
+    // Returns the line index and character column of the match
+    pub fn find(&self, query: &str, line: usize, column: usize) -> Option<(usize, usize)> {
+        if query.is_empty() {
+            return None;
+        }
+        let line = line.min(self.lines.len() - 1);
+        let text = &self.lines[line];
+        let start = byte_index(text, column).unwrap_or(text.len());
+        if let Some(index) = text[start..].find(query) { // Rest of the starting line
+            return Some((line, column + text[start..start + index].chars().count()));
+        }
+        (line + 1..self.lines.len())
+            .chain(0..=line) // Wrap round, ending with the start of the starting line
+            .find_map(|index| {
+                let text = &self.lines[index];
+                text.find(query).map(|byte| (index, text[..byte].chars().count()))
+            })
+    }
+
+    // What is at path, following symlinks
+    pub fn file_kind(path: &str) -> FileKind {
+        use std::os::unix::fs::FileTypeExt;
+        let file_type = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => return FileKind::Missing,
+        };
+        if file_type.is_file() {
+            FileKind::Regular
+        } else if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            FileKind::Special("device")
+        } else if file_type.is_socket() {
+            FileKind::Special("socket")
+        } else if file_type.is_fifo() {
+            FileKind::Special("named pipe")
+        } else {
+            FileKind::Special("special file")
+        }
+    }
+
+    // Looks next to the file for a lock or swap file left by another editor
+    // patterns is a comma separated list of file names with {name} standing for the document's file name
+    pub fn find_lock_file(&self, patterns: &str) -> Option<std::path::PathBuf> {
+        let path = std::path::Path::new(&self.path);
+        let name = path.file_name()?.to_string_lossy().to_string();
+        let directory = path.parent().unwrap_or(std::path::Path::new(""));
+        patterns.split(',')
+            .map(|pattern| pattern.trim())
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| directory.join(pattern.replace("{name}", &name)))
+            .find(|lock| lock.symlink_metadata().is_ok()) // Emacs locks are dangling symlinks, so don't follow them
+    }
+
+    // Creates any missing directories above the document's path
+    pub fn create_parent_dirs(&self) -> Result<(), std::io::Error> {
+        match std::path::Path::new(&self.path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+            _ => Ok(()), // Path is in the current directory
+        }
+    }
+
+    // Keeps the last keep versions of the file on disk as path.1 (newest) to path.keep (oldest)
+    // Call before saving -- the current file becomes path.1 and older backups shift up, dropping any past keep
+    pub fn rotate_backups(&self, keep: usize) -> Result<(), std::io::Error> {
+        let path = std::path::Path::new(&self.path);
+        if keep == 0 || !path.is_file() { // Nothing to back up
+            return Ok(());
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+        // Find existing numbered backups of this file
+        let mut numbers = vec![];
+        for entry in std::fs::read_dir(&directory)?.flatten() {
+            let entry_name = entry.file_name().to_string_lossy().to_string();
+            let number = entry_name.strip_prefix(&name)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .and_then(|rest| rest.parse::<usize>().ok())
+                .filter(|number| *number > 0);
+            if let Some(number) = number {
+                numbers.push(number);
             }
-            output_file.write_all(line.as_bytes()).expect("Could not write to file"); // Write line to file
-            first_line_written = true; // Set first line written to true (doesn't matter if it was already true)
         }
+        numbers.sort_unstable();
+        let backup = |number: usize| directory.join(format!("{}.{}", name, number)); // Path of a numbered backup
+        // Shift backups up by one starting from the oldest, deleting those that fall off the end
+        for number in numbers.into_iter().rev() {
+            if number >= keep {
+                std::fs::remove_file(backup(number))?;
+            } else {
+                std::fs::rename(backup(number), backup(number + 1))?;
+            }
+        }
+        std::fs::copy(path, backup(1))?;
+        Ok(())
+    }
+
+    // Writes lines start..end to another file, leaving the document and its path alone
+    pub fn write_lines(&self, start: usize, end: usize, path: &str) -> Result<(), std::io::Error> {
+        let mut text = self.lines[start..end].join(self.line_ending.as_str());
+        text.push_str(self.line_ending.as_str()); // An extract always ends with a newline
+        std::fs::write(path, text)
+    }
+
+    // Save open document to file, stripping trailing spaces and tabs from the saved lines (not the document's) if trim_whitespace is set
+    // The text goes to a temporary file next to it first, which is then renamed over the file, so a failed write leaves the old contents intact
+    pub fn save(&self, trim_whitespace: bool) -> Result<(), std::io::Error> {
+        if self.path.is_empty() { // Untitled, the caller has to ask for a name first
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no file name to save to"));
+        }
+        let path = std::fs::canonicalize(&self.path).unwrap_or_else(|_| self.path.clone().into()); // Through symlinks, so a link stays a link
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let temp = path.with_file_name(format!(".{}.bim-save", name));
+        let result = write_and_replace(&temp, &path, self.to_text_trimmed(trim_whitespace).as_bytes());
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp); // Don't leave a half-written temporary file behind
+        }
+        result
+    }
+}
+
+// Writes contents to temp, gives it the permissions of the file at path (if there is one) and renames it over path
+fn write_and_replace(temp: &std::path::Path, path: &std::path::Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = std::fs::File::create(temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?; // On disk before it replaces anything
+    if let Ok(metadata) = std::fs::metadata(path) { // Keep an executable script executable
+        std::fs::set_permissions(temp, metadata.permissions())?;
+    }
+    std::fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes contents to a fresh temp file and returns its path
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    // Loads and saves the file at path, returning the bytes written back
+    fn round_trip(path: &str) -> String {
+        Document::from_file(path).unwrap().save(false).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        saved
+    }
+
+    #[test]
+    fn empty_buffer_saves_zero_bytes() {
+        let path = temp_file("empty_buffer", "");
+        let document = Document { path: path.clone(), ..Document::default() };
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(round_trip(&path), "");
+    }
+
+    #[test]
+    fn empty_loaded_file_saves_zero_bytes() {
+        let path = temp_file("empty_loaded", "");
+        let document = Document::from_file(&path).unwrap();
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(round_trip(&path), "");
+    }
+
+    #[test]
+    fn empty_buffer_with_trailing_newline_saves_one_newline() {
+        let path = temp_file("empty_trailing", "");
+        let document = Document { path: path.clone(), trailing_newline: true, ..Document::default() };
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "\n");
+        assert_eq!(round_trip(&path), "\n");
+    }
+
+    #[test]
+    fn new_files_end_with_a_newline() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_new_file", std::process::id())).to_string_lossy().to_string();
+        let mut document = Document::from_file(&path).unwrap(); // Not on disk yet
+        document.lines = vec!["new".to_string()];
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn single_blank_line_round_trips() {
+        let path = temp_file("single_blank", "\n");
+        let document = Document::from_file(&path).unwrap();
+        assert_eq!(document.lines, vec![""]);
+        assert!(document.trailing_newline);
+        assert_eq!(round_trip(&path), "\n");
+    }
+
+    #[test]
+    fn trailing_newline_or_its_absence_round_trips() {
+        for text in ["a\nb\n", "a\nb"] {
+            let path = temp_file("trailing_newline", text);
+            assert_eq!(round_trip(&path), text);
+        }
+    }
+
+    #[test]
+    fn trailing_blank_lines_round_trip() {
+        let path = temp_file("trailing_blank", "a\n\n");
+        assert_eq!(round_trip(&path), "a\n\n");
+    }
+
+    #[test]
+    fn classic_mac_line_endings_split_and_round_trip() {
+        let path = temp_file("classic_mac", "one\rtwo\rthree\r");
+        let document = Document::from_file(&path).unwrap();
+        assert_eq!(document.lines, vec!["one", "two", "three"]);
+        assert_eq!(document.line_ending, LineEnding::Cr);
+        assert_eq!(round_trip(&path), "one\rtwo\rthree\r");
+    }
+
+    #[test]
+    fn windows_line_endings_round_trip() {
+        let path = temp_file("windows", "one\r\ntwo\nthree\r\n");
+        let document = Document::from_file(&path).unwrap();
+        assert_eq!(document.lines, vec!["one", "two", "three"]);
+        assert_eq!(document.line_ending, LineEnding::CrLf);
+        assert_eq!(round_trip(&path), "one\r\ntwo\r\nthree\r\n"); // The odd \n out follows the rest
+        assert_eq!(Document::from_text("a\r\nb\n").line_ending, LineEnding::Lf);
+        assert_eq!(Document::default().line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn stray_carriage_return_splits_line() {
+        let document = Document::from_text("one\rtwo\nthree\n");
+        assert_eq!(document.lines, vec!["one", "two", "three"]);
+        assert_eq!(document.line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn newline_count_matches_wc() {
+        for (text, expected) in [("", 0), ("\n", 1), ("a", 0), ("a\nb", 1), ("a\nb\n", 2), ("a\n\n", 2)] {
+            assert_eq!(Document::from_text(text).newline_count(), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn finds_sibling_lock_file() {
+        let path = temp_file("locked", "text\n");
+        let document = Document::from_file(&path).unwrap();
+        assert_eq!(document.find_lock_file(".{name}.swp, {name}.lock"), None);
+        let lock = format!("{}.lock", path);
+        std::fs::write(&lock, "").unwrap();
+        assert_eq!(document.find_lock_file(".{name}.swp, {name}.lock"), Some(std::path::PathBuf::from(&lock)));
+        std::fs::remove_file(&lock).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn line_access_out_of_range_does_nothing() {
+        let mut document = Document::from_text("ab\né\n");
+        assert_eq!(document.line(2), None);
+        assert_eq!(document.line_len(2), 0);
+        assert!(!document.insert_char(2, 0, 'x'));
+        assert!(!document.insert_char(0, 3, 'x'));
+        assert!(!document.insert_char(1, 2, 'x')); // Past the end of é, which is one column wide
+        assert!(!document.insert_char(0, 0, '\n'));
+        assert!(!document.insert_char(0, 0, '\r'));
+        assert_eq!(document.remove_char(0, 2), None);
+        assert!(!document.split_line(5, 0));
+        assert_eq!(document.join_with_previous(0), None);
+        assert_eq!(document.join_with_previous(2), None);
+        assert_eq!(document.lines, vec!["ab", "é"]);
+
+        assert!(document.split_line(0, 1));
+        assert_eq!(document.join_with_previous(1), Some(1));
+        assert_eq!(document.remove_char(1, 0), Some('é'));
+        assert_eq!(document.lines, vec!["ab", ""]);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let mut document = Document::from_text("hé🎉");
+        assert_eq!(document.line_len(0), 3);
+        assert!(document.insert_char(0, 2, 'x'));
+        assert_eq!(document.remove_char(0, 3), Some('🎉'));
+        assert!(document.split_line(0, 2));
+        assert_eq!(document.lines, vec!["hé", "x"]);
+        assert_eq!(document.join_with_previous(1), Some(2));
+        assert_eq!(Document::from_text("é ab").find("ab", 0, 1), Some((0, 2)));
+    }
+
+    #[test]
+    fn trim_trailing_blank_lines_leaves_one_newline() {
+        for (text, expected) in [("a\n\n \n\t\n", "a\n"), ("a", "a\n"), ("a\n", "a\n"), ("\n\n", ""), ("", ""), ("a\n\nb\n\n", "a\n\nb\n")] {
+            let mut document = Document::from_text(text);
+            assert_eq!(document.trim_trailing_blank_lines(), text != expected, "{:?}", text);
+            assert_eq!(document.to_text(), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn file_kind_by_metadata() {
+        let directory = std::env::temp_dir();
+        let file = temp_file("kind", "");
+        assert_eq!(Document::file_kind(&file), FileKind::Regular);
+        assert_eq!(Document::file_kind(&directory.to_string_lossy()), FileKind::Directory);
+        assert_eq!(Document::file_kind("/dev/null"), FileKind::Special("device"));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(Document::file_kind(&file), FileKind::Missing);
+    }
+
+    #[test]
+    fn find_wraps_round() {
+        let document = Document::from_text("ab ab\nx\nab");
+        assert_eq!(document.find("ab", 0, 0), Some((0, 0)));
+        assert_eq!(document.find("ab", 0, 1), Some((0, 3)));
+        assert_eq!(document.find("ab", 0, 4), Some((2, 0)));
+        assert_eq!(document.find("ab", 2, 1), Some((0, 0)));
+        assert_eq!(document.find("x", 1, 1), Some((1, 0)));
+        assert_eq!(document.find("zz", 0, 0), None);
+        assert_eq!(document.find("", 0, 0), None);
+    }
+
+    #[test]
+    fn save_and_load_errors_are_returned() {
+        let mut document = Document::from_text("text");
+        document.path = std::env::temp_dir().join("bim_missing_directory").join("file").to_string_lossy().to_string();
+        assert_eq!(document.save(false).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        document.path = String::new();
+        assert_eq!(document.save(false).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        let path = temp_file("invalid_utf8", "");
+        std::fs::write(&path, [0xff, 0xfe]).unwrap();
+        assert!(Document::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn text_between_and_remove_between() {
+        let mut document = Document::from_text("héllo\nworld\nagain");
+        assert_eq!(document.text_between((0, 1), (0, 4)), "éll");
+        assert_eq!(document.text_between((0, 3), (2, 2)), "lo\nworld\nag");
+        assert_eq!(document.text_between((1, 5), (2, 0)), "\n");
+        document.remove_between((0, 3), (2, 2));
+        assert_eq!(document.lines, vec!["hélain"]);
+        document.remove_between((0, 0), (0, 99));
+        assert_eq!(document.lines, vec![""]);
+    }
+
+    #[test]
+    fn failed_save_leaves_the_file_intact() {
+        let path = temp_file("failed_save", "original\n");
+        let temp = std::path::Path::new(&path).with_file_name(format!(".bim_test_{}_failed_save.bim-save", std::process::id()));
+        std::fs::create_dir(&temp).unwrap(); // The temporary file can't be created where a directory is
+        let mut document = Document::from_file(&path).unwrap();
+        document.lines[0] = "changed".to_string();
+        assert!(document.save(false).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "original\n");
+
+        std::fs::remove_dir(&temp).unwrap();
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "changed\n");
+        assert!(!temp.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_can_trim_trailing_whitespace() {
+        let path = temp_file("trim_whitespace", "");
+        let lines = vec!["fn a() { \t".to_string(), " \t ".to_string(), "\tb\t".to_string(), "c".to_string()];
+        let document = Document { path: path.clone(), lines: lines.clone(), ..Document::default() };
+        document.save(true).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fn a() {\n\n\tb\nc\n");
+        assert_eq!(document.lines, lines); // Only the saved copy is trimmed
+        document.save(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fn a() { \t\n \t \n\tb\t\nc\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lazy_documents_read_lines_as_needed() {
+        let lines: Vec<String> = (0..3000).map(|n| format!("line {}", n)).collect();
+        let path = temp_file("lazy", &(lines.join("\r\n") + "\r\n"));
+        let mut document = Document::from_file_lazy(&path).unwrap();
+        assert_eq!(document.lines.len(), 3000);
+        assert_eq!(document.line_ending, LineEnding::CrLf);
+        assert!(document.trailing_newline);
+        assert!(document.lines.iter().all(|line| line.is_empty())); // Nothing read yet
+        document.load_lines(0..10).unwrap();
+        assert_eq!(document.lines[..10 + LAZY_MARGIN], lines[..10 + LAZY_MARGIN]);
+        assert_eq!(document.lines[10 + LAZY_MARGIN], "");
+        document.load_lines(2990..3000).unwrap();
+        assert_eq!(document.lines[0], ""); // The earlier lines are let go
+        assert_eq!(document.lines[2999], "line 2999");
+        document.materialize().unwrap();
+        assert!(!document.is_lazy());
+        assert_eq!(document.lines, lines);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_file("lazy_single_line", "no newline");
+        assert!(!Document::from_file_lazy(&path).unwrap().is_lazy()); // Nothing to index
+        std::fs::remove_file(&path).unwrap();
     }
 }
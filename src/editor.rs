@@ -1,171 +1,900 @@
 // editor.rs
 // Handles editor instance and utils -- input, cursor movement, rendering
 
+use std::collections::HashMap;
 use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use termion::event::Key;
-use termion::input::TermRead;
+use termion::event::{Event, Key, MouseButton, MouseEvent};
+use termion::input::{Events, TermRead};
 use termion::raw::IntoRawMode;
 
-use crate::{terminal, Document};
+use crate::document::{self, FileKind, LineChange};
+use crate::highlight::Syntax;
+use crate::history::{Change, History, View};
+use crate::observer::Observer;
+use crate::terminal::Color;
+use crate::{finder, preview, recent, terminal, Action, Config, Document};
+
+const START_PAGE_RECENT: usize = 5; // Recent files listed on the start page
+const IDLE_QUIT_WARNING: u64 = 10; // Seconds before an idle quit that the countdown shows
+const STATUS_MESSAGE_SECONDS: u64 = 5; // How long a status message shows before the status bar goes back to normal
+const CLOCK_WIDTH: usize = 8; // Columns the status bar clock takes, HH:MM:SS
+const DOUBLE_CLICK_MS: u64 = 400; // Longest gap between clicks on the same spot that still counts as a double or triple click
+const OPTIONS_HELP: &[&str] = &[ // Keys listed in the options menu, split into pages that fit the status bar
+    "ESC: Back to Editor", "a: Save and Exit", "s: Save", "w: Write Lines To", "q: Quit", "r: Reload", "d: Discard Changes",
+    "v: Read Only", "t: Trim Whitespace On Save", "i: Auto Indent", "m: Line Markers", "h: Word Highlight", "l: Line Numbers",
+    "g: Change Gutter", "c: Go To Column", "n: Insert Character N Times", "=: Align", "f: Trim Blank Lines At End",
+    "!: Insert Command Output", "|: Filter Through Command",
+];
+
+// One screen row of the editor's layout
+pub struct VisibleRow {
+    pub screen_row: usize,     // Row on screen, 0 is the top
+    pub line: Option<usize>,   // Index of the document line shown, None for rows past the end
+    pub columns: Range<usize>, // Screen columns of the line that are shown, with tabs expanded
+}
+
+// What a screen row was last drawn with, so draw only rewrites the rows that differ from it
+#[derive(Clone, PartialEq)]
+enum DrawnRow {
+    Line {
+        number: String,             // Line number in the gutter, empty without line numbers
+        change: Option<Color>,      // Color of the change bar, None without the change gutter or for unchanged lines
+        left_marker: &'static str,  // Marker for content scrolled off the left edge, drawn before the text
+        text: String,               // Visible part of the line
+        foregrounds: Vec<Color>,    // Color of each visible character
+        backgrounds: Vec<Color>,    // Background of each visible character
+        marker: &'static str,       // Continuation marker after the text
+    },
+    Empty(String),                  // Past the end of the document, with the marker shown
+}
+
+// A file open in the background, kept as it was left so switching back picks up where it was
+struct Buffer {
+    document: Document, // The file's document
+    history: History,   // Its undo history
+    dirty: bool,        // Unsaved changes?
+    view: View,         // Cursor and scroll position
+    h_scroll: usize,    // Horizontal scroll
+}
 
 pub struct Editor {
-    running: bool,                    // Is the editor running?
-    options_mode: bool,               // Is the editor in options mode?
-    scroll_position: usize,           // How many lines down the document is scrolled
-    status_bar: String,               // The status bar text
-    pub terminal: terminal::Terminal, // The terminal instance
-    pub open_document: Document,      // The open document
+    running: bool,                       // Is the editor running?
+    options_mode: bool,                  // Is the editor in options mode?
+    options_page: usize,                 // Page of the options menu help showing
+    scroll_position: usize,              // How many lines down the document is scrolled
+    h_scroll: usize,                     // How many columns right the document is scrolled
+    drawn_rows: Vec<Option<DrawnRow>>,   // What each screen row above the status bar shows, None for rows to draw afresh
+    status_bar: String,                  // The status bar text
+    status_message: Option<(String, std::time::Instant)>, // One-off message shown in the status bar for a few seconds, and when it was set
+    last_action: Option<Action>,         // The last mutating action, replayed by repeat
+    insert_run_open: bool,               // Is the last action an insert run still being typed?
+    last_insert_time: std::time::Instant, // When text was last typed, a long pause starts a new undo step
+    dirty: bool,                         // Has the document changed since it was last saved?
+    quit_pending: bool,                  // Has quit been pressed once with unsaved changes?
+    bell: bool,                          // Should the next frame flash the status bar?
+    quote_next: bool,                    // Insert the next key literally instead of acting on it?
+    selection_anchor: Option<terminal::Position>, // Where the selection started (y is the document line), None when nothing is selected
+    clipboard: String,                   // Text copied or cut, pasted with Ctrl+U
+    registers: HashMap<char, Vec<String>>, // Named (a-z) and numbered (0-9) registers, holding the lines of the text put in them
+    register_next: bool,                 // Is the next key the name of a register?
+    register: Option<char>,              // Register the next copy, cut or paste uses instead of the clipboard
+    continued_comment: Option<String>,   // Comment prefix Enter just added, removed again if Enter follows straight away
+    moved_last: bool,                    // Was the last key a cursor movement?
+    last_click: Option<(std::time::Instant, (usize, usize), usize)>, // When and where (column, line) the last click was, and how many clicks in a row it made
+    last_key_time: std::time::Instant,   // When the last key was read, for the idle quit timeout
+    normal_mode: bool,                   // In vim mode, are keys commands (normal mode) rather than text (insert mode)?
+    pending_operator: Option<char>,      // d or y typed in normal mode, waiting for the motion it applies to
+    input: Events<terminal::RawStdin>,   // Key events from stdin, kept between reads so no buffered byte is lost
+    observers: Vec<Box<dyn Observer>>,   // Told about changes, saves, cursor moves and mode changes
+    history: History,                    // Edits that can be undone and redone
+    buffers: Vec<Buffer>,                // The other open files, in order, with the open document left out
+    active_buffer: usize,                // Where the open document goes among the other open files
+    last_buffer: usize,                  // Open file that was active before the current one, for switching back
+    search_query: Option<String>,        // Text being searched for, Some while in search mode
+    search_origin: View,                 // Where the cursor was when the search started, returned to on ESC
+    search_failed: bool,                 // Did the last search find nothing?
+    preferred_column: Option<usize>,     // Column vertical movement returns to, set by the first Up/Down after other keys
+    line_markers: bool,                  // Mark lines that continue past the right edge?
+    change_gutter: bool,                 // Mark lines changed since load or the last save in a gutter?
+    line_numbers: bool,                  // Number the lines in a gutter?
+    pub read_only: bool,                 // Ignore keys that change the document and refuse to save?
+    recent_files: Vec<String>,           // Recently opened files, most recent first
+    start_page_selection: Option<usize>, // Recent file highlighted on the start page
+    pub base_directory: Option<PathBuf>, // Directory relative paths resolve against (process cwd if None)
+    pub config: Config,                  // User settings
+    pub terminal: terminal::Terminal,    // The terminal instance
+    pub open_document: Document,         // The open document
 }
 
 impl Default for Editor {
     fn default() -> Self {
-        Self {
+        Self::with_config(Config::load())
+    }
+}
+
+impl Editor {
+    // Creates an editor on the real terminal with already loaded settings
+    pub fn with_config(config: Config) -> Self {
+        let markdown_preview = config.markdown_preview;
+        let mut editor = Self::new(terminal::Terminal::default(), config);
+        editor.recent_files = recent::load();
+        if markdown_preview {
+            editor.add_observer(Box::new(preview::MarkdownPreview));
+        }
+        editor
+    }
+
+    // Creates an editor drawing to terminal with the given settings
+    pub fn new(terminal: terminal::Terminal, config: Config) -> Self {
+        let config_error = match &config.errors[..] { // Mention the first problem with ~/.bimrc, and how many others there are
+            [] => None,
+            [error] => Some(format!("{} ", error)),
+            [error, others @ ..] => Some(format!("{} (and {} more) ", error, others.len())),
+        };
+        let mut editor = Self {
             running: true,
             options_mode: false,
+            options_page: 0,
             scroll_position: 0,
-            status_bar: format!("ESC to quit."),
-            terminal: terminal::Terminal::default(),
+            h_scroll: 0,
+            drawn_rows: vec![],
+            status_bar: "ESC to quit.".to_string(),
+            status_message: None,
+            last_action: None,
+            insert_run_open: false,
+            last_insert_time: std::time::Instant::now(),
+            dirty: false,
+            quit_pending: false,
+            bell: false,
+            quote_next: false,
+            selection_anchor: None,
+            clipboard: String::new(),
+            registers: HashMap::new(),
+            register_next: false,
+            register: None,
+            continued_comment: None,
+            moved_last: false,
+            last_click: None,
+            last_key_time: std::time::Instant::now(),
+            normal_mode: config.vim_mode, // Vim mode starts out in normal mode
+            pending_operator: None,
+            input: terminal::RawStdin.events(),
+            observers: vec![],
+            history: History::default(),
+            buffers: vec![],
+            active_buffer: 0,
+            last_buffer: 0,
+            search_query: None,
+            search_origin: View { cursor: terminal::Position::default(), scroll_position: 0 },
+            search_failed: false,
+            preferred_column: None,
+            line_markers: true,
+            change_gutter: false,
+            line_numbers: false,
+            read_only: false,
+            recent_files: vec![],
+            start_page_selection: None,
+            base_directory: None,
+            config,
+            terminal,
             open_document: Document::default(),
+        };
+        if let Some(message) = config_error {
+            editor.set_status_message(message);
         }
+        editor
     }
-}
 
-impl Editor {
     // Starts raw mode then main loop
     pub fn run(&mut self) {
         let _stdout = std::io::stdout().into_raw_mode().unwrap(); // Start raw mode
+        if self.config.auto_save_on_focus_lost { // Focus events are only needed for auto-save
+            self.terminal.set_focus_reporting(true);
+        }
+        self.terminal.watch_resize();
         loop { // Main loop
+            // Pick up a new terminal size and keep the cursor on screen
+            if self.terminal.refresh_size() {
+                self.clamp_viewport(); // Drawing scrolls horizontally to the cursor if it's now past the right edge
+                self.redraw_all();
+            }
+            // Save and quit once no key has been pressed for the idle timeout
+            let idle_remaining = self.idle_quit_remaining();
+            if idle_remaining == Some(0) {
+                self.options_mode = false;
+                let saved = self.save_all();
+                self.running = !saved; // Stay open if a save failed
+                self.last_key_time = std::time::Instant::now(); // Don't retry a failed save every second
+            }
             // Set the status bar
-            if !self.options_mode { // Options mode
-                self.status_bar = format!(
-                    "({}/{}) ESC for Options ",
-                    self.terminal.get_cursor_position().y + 1 + self.scroll_position,
-                    self.open_document.lines.len()
-                );
+            self.expire_status_message();
+            if let Some(remaining) = idle_remaining.filter(|remaining| *remaining > 0 && *remaining <= IDLE_QUIT_WARNING) { // Idle countdown
+                self.status_bar = format!("No input, saving and quitting in {}s (press any key to stay) ", remaining);
+            } else if let Some(query) = &self.search_query { // Search mode
+                self.status_bar = format!("Search: {}{} ", query, if self.search_failed { " (not found)" } else { "" });
+            } else if let (false, Some((message, _))) = (self.options_mode, &self.status_message) { // Pending message
+                self.status_bar = message.clone();
+            } else if !self.options_mode { // Options mode
+                self.status_bar = self.format_status(&self.config.status_format);
+            } else if self.quit_pending { // Quit confirmation
+                self.status_bar =
+                    "[Options] Unsaved changes! q: Quit without saving / any other key: Cancel ".to_string();
             } else { // Editor mode
-                self.status_bar = format!(
-                    "[Options] ESC: Back to Editor / a: Save and Exit / s: Save / q: Quit "
-                );
+                self.status_bar = self.options_help();
             }
 
-            // Draw the editor
-            if let Err(error) = self.draw() {
-                panic!("{}", error);
+            // Draw the editor, unless more keys arrive within the throttle window -- they're handled first and drawn together
+            let throttle = self.config.redraw_throttle_ms;
+            let input_waiting = (throttle > 0 && self.running && !self.bell && terminal::input_pending(throttle))
+                || (self.config.coalesce_movement && self.moved_last && terminal::input_pending(0)); // Held arrow keys draw once per batch
+            if !input_waiting {
+                if let Err(error) = self.draw() {
+                    panic!("{}", error);
+                }
+            }
+            // Flash the status bar for one brief frame, then draw it normally again
+            if self.bell {
+                std::thread::sleep(std::time::Duration::from_millis(150));
+                self.bell = false;
+                if let Err(error) = self.draw() {
+                    panic!("{}", error);
+                }
             }
             // Check for exit
             if !self.running {
+                self.terminal.restore();
+                recent::save(&self.recent_files);
                 self.terminal.clear();
                 self.terminal.set_cursor_position(terminal::Position::default());
                 // Show goodbye in production
                 if !cfg!(debug_assertions) {
-                    println!("Goodbye.\r");
+                    self.terminal.write("Goodbye.\r\n");
+                    let _ = self.terminal.flush();
                 }
                 break;
             }
+            // Wait for a key, going round again to redraw straight away if the terminal is resized meanwhile (the signal cuts the wait short)
+            // With the clock or the idle timeout on, or a message up, also go round again once a second without a key so they keep ticking
+            let ticking = self.config.status_clock || self.config.idle_quit_seconds > 0 || self.status_message.is_some();
+            if !terminal::input_pending(if ticking { millis_to_next_second() } else { u64::MAX }) {
+                continue;
+            }
             // Process inputs
-            if self.options_mode { // Options mode
-                if let Err(error) = self.process_options() {
-                    panic!("{}", error);
+            let result = match self.read_event() { // Read keystroke or click
+                Ok(Event::Key(key)) => self.handle_key(key),
+                Ok(Event::Mouse(MouseEvent::Press(MouseButton::Left, x, y))) => {
+                    self.click(x.saturating_sub(1) as usize, y.saturating_sub(1) as usize); // termion counts from 1
+                    Ok(())
                 }
-            } else { // Editor mode
-                if let Err(error) = self.process_input() {
-                    panic!("{}", error);
+                Ok(_) => Ok(()), // Other buttons, drags and releases
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                panic!("{}", error);
+            }
+        }
+    }
+
+    // Seconds left before the idle timeout saves and quits, None if it's off
+    fn idle_quit_remaining(&self) -> Option<u64> {
+        match self.config.idle_quit_seconds {
+            0 => None,
+            timeout => Some(timeout.saturating_sub(self.last_key_time.elapsed().as_secs())),
+        }
+    }
+
+    // Handles a key in whichever mode the editor is in, then tells observers if the cursor or mode changed
+    pub fn handle_key(&mut self, key: Key) -> Result<(), std::io::Error> {
+        self.load_visible_lines(); // So movement sees the lines it lands on
+        let (cursor, mode) = (self.cursor(), self.mode());
+        if self.search_query.is_some() { // Search mode
+            self.process_search(key)?;
+        } else if self.options_mode { // Options mode
+            self.process_options(key)?;
+        } else { // Editor mode
+            self.process_input(key)?;
+        }
+        if self.observers.is_empty() {
+            return Ok(());
+        }
+        let (new_cursor, new_mode) = (self.cursor(), self.mode());
+        for observer in &mut self.observers {
+            if (new_cursor.x, new_cursor.y) != (cursor.x, cursor.y) {
+                observer.cursor_moved(new_cursor.y, new_cursor.x);
+            }
+            if new_mode != mode {
+                observer.mode_changed(new_mode);
+            }
+        }
+        Ok(())
+    }
+
+    // Registers an observer to be told about what the editor does
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    // Tells observers the document's text changed
+    fn document_changed(&mut self) {
+        for observer in &mut self.observers {
+            observer.document_changed(&self.open_document);
+        }
+    }
+
+    // Name of the current mode, as shown by {mode}
+    fn mode(&self) -> &'static str {
+        if self.search_query.is_some() {
+            return "SEARCH";
+        }
+        match (self.options_mode, self.config.vim_mode, self.normal_mode) {
+            (true, _, _) => "OPTIONS",
+            (false, true, true) => "NORMAL",
+            (false, true, false) => "INSERT",
+            (false, false, _) => "EDIT",
+        }
+    }
+
+    // Fills in a status bar template
+    // Placeholders are {path} {line} {col} {total} {newlines} {modified} {mode} {ff} and {enc}, unknown ones are left as written
+    // {total} counts the lines the cursor can be on, {newlines} counts line endings in the saved file like wc -l
+    pub fn format_status(&self, template: &str) -> String {
+        let position = self.terminal.get_cursor_position();
+        let mut status = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            status.push_str(&rest[..start]); // Text before the placeholder
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => { // Unclosed brace, keep the remainder as written
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+            let value = match &rest[start + 1..end] {
+                "path" => self.open_document.path.clone(),
+                "buffers" if !self.buffers.is_empty() => format!( // Which of the open files this is, only with more than one
+                    "[{}/{}] {} ",
+                    self.active_buffer + 1,
+                    self.buffers.len() + 1,
+                    if self.open_document.path.is_empty() { "untitled" } else { &self.open_document.path }
+                ),
+                "buffers" => String::new(),
+                "line" => (position.y + self.scroll_position + 1).to_string(),
+                "col" => (position.x + 1).to_string(),
+                "total" => self.open_document.lines.len().to_string(),
+                "newlines" => self.open_document.newline_count().to_string(),
+                "modified" => if self.dirty { "[modified]".to_string() } else { "".to_string() },
+                "readonly" => if self.read_only { "[readonly]".to_string() } else { "".to_string() },
+                "mode" => self.mode().to_string(),
+                "ff" => self.open_document.line_ending.name().to_string(),
+                "enc" => "utf-8".to_string(),
+                _ => rest[start..=end].to_string(), // Unknown placeholder
+            };
+            status.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        status.push_str(rest);
+        status
+    }
+
+    // Shows message in the status bar for the next few seconds
+    fn set_status_message(&mut self, message: String) {
+        self.status_message = Some((message, std::time::Instant::now()));
+    }
+
+    // Tells the user a key was ignored because the document is read-only
+    fn refuse_edit(&mut self) {
+        self.set_status_message("Read-only (v in the options menu allows editing) ".to_string());
+    }
+
+    // Drops the status bar message once it has been up long enough
+    fn expire_status_message(&mut self) {
+        if self.status_message.as_ref().is_some_and(|(_, set)| set.elapsed().as_secs() >= STATUS_MESSAGE_SECONDS) {
+            self.status_message = None;
+        }
+    }
+
+    // Writes text in a foreground color, then goes back to the default color
+    fn write_colored(&mut self, text: &str, color: Color) {
+        self.terminal.set_foreground(color);
+        self.terminal.write(text);
+        self.terminal.set_foreground(Color::Reset);
+    }
+
+    // Writes document text with control characters shown as inverted caret notation (^A), so they can't act on the terminal
+    // Tabs are written as they are, the stored text is never changed
+    fn write_escaped(&mut self, text: &str) {
+        let mut rest = text;
+        while let Some((index, c)) = rest.char_indices().find(|(_, c)| c.is_control() && *c != '\t') {
+            self.terminal.write(&rest[..index]); // Text before the control character
+            self.terminal.set_inverted(true);
+            self.terminal.write(&caret_notation(c));
+            self.terminal.set_inverted(false);
+            rest = &rest[index + c.len_utf8()..];
+        }
+        self.terminal.write(rest);
+    }
+
+    // Writes text with a foreground and background color for each character, changing colors only where they differ
+    // Colors are given per character of what is written, so horizontal scrolling never cuts a color change in half
+    fn write_with_colors(&mut self, text: &str, foregrounds: &[Color], backgrounds: &[Color]) {
+        let mut run_start = 0; // Byte index the current run of one pair of colors starts at
+        let mut run_colors = (Color::Reset, Color::Reset);
+        for ((index, _), colors) in text.char_indices().zip(foregrounds.iter().copied().zip(backgrounds.iter().copied())) {
+            if colors != run_colors {
+                self.write_escaped(&text[run_start..index]);
+                if colors.0 != run_colors.0 {
+                    self.terminal.set_foreground(colors.0);
+                }
+                if colors.1 != run_colors.1 {
+                    self.terminal.set_background(colors.1);
                 }
+                run_start = index;
+                run_colors = colors;
+            }
+        }
+        self.write_escaped(&text[run_start..]);
+        if run_colors.0 != Color::Reset {
+            self.terminal.set_foreground(Color::Reset);
+        }
+        if run_colors.1 != Color::Reset {
+            self.terminal.set_background(Color::Reset);
+        }
+    }
+
+    // Identifier (letters, digits and _) the cursor is on or just after, None if it isn't on one
+    fn word_under_cursor(&self) -> Option<String> {
+        let cursor = self.cursor();
+        let line = self.open_document.line(cursor.y)?;
+        let x = document::byte_index(line, cursor.x).unwrap_or(line.len()); // Cursor as a byte offset
+        let start = line[..x].char_indices().rev().take_while(|(_, c)| is_word_char(*c)).last().map_or(x, |(index, _)| index);
+        let end = x + line[x..].char_indices().find(|(_, c)| !is_word_char(*c)).map_or(line.len() - x, |(index, _)| index);
+        (start < end).then(|| line[start..end].to_string())
+    }
+
+    // Screen column (before horizontal scrolling) the cursor is drawn at, which is further right than its character column after tabs
+    fn cursor_display_column(&self) -> usize {
+        let cursor = self.cursor();
+        display_column(self.open_document.line(cursor.y).unwrap_or_default(), cursor.x, self.tab_width())
+    }
+
+    // Character column of line index drawn at or just before screen column display, for moving between lines
+    fn column_at_display(&self, index: usize, display: usize) -> usize {
+        column_at_display(self.open_document.line(index).unwrap_or_default(), display, self.tab_width())
+    }
+
+    // Columns between tab stops as drawn, display_tab_width while read-only if it's set
+    fn tab_width(&self) -> usize {
+        match self.config.display_tab_width {
+            0 => self.config.tab_width,
+            width if self.read_only => width,
+            _ => self.config.tab_width,
+        }
+    }
+
+    // Columns available for text once the gutter is taken out
+    fn text_width(&self) -> usize {
+        self.terminal.width.saturating_sub(self.terminal.x_offset)
+    }
+
+    // Maps each screen row above the status bar to the document content it shows
+    // This is the layout draw paints from, kept separate so other renderers and tests can reuse it
+    pub fn visible_rows(&self) -> impl Iterator<Item = VisibleRow> + '_ {
+        let text_width = self.text_width();
+        (0..self.terminal.height.saturating_sub(1)).map(move |screen_row| {
+            let line_index = screen_row + self.scroll_position; // Adjust for scroll position
+            match self.open_document.lines.get(line_index) {
+                Some(line) => VisibleRow {
+                    screen_row,
+                    line: Some(line_index),
+                    columns: { // Screen columns from the horizontal scroll to the right edge, empty if the line ends before the scroll
+                        let len = display_column(line, usize::MAX, self.tab_width());
+                        self.h_scroll.min(len)..len.min(self.h_scroll + text_width)
+                    },
+                },
+                None => VisibleRow { screen_row, line: None, columns: 0..0 }, // Past the end of the document
+            }
+        })
+    }
+
+    // Reads the lines around the view of a lazily loaded document, a page either side of it
+    fn load_visible_lines(&mut self) {
+        let height = self.terminal.height;
+        let range = self.scroll_position.saturating_sub(height)..self.scroll_position + 2 * height;
+        if let Err(error) = self.open_document.load_lines(range) {
+            self.set_status_message(format!("Could not read {}: {} ", self.open_document.path, error));
+        }
+    }
+
+    // Reads all of a lazily loaded document, for things that need every line
+    // Returns false, with the error in the status bar, if it couldn't be read
+    fn materialize(&mut self) -> bool {
+        match self.open_document.materialize() {
+            Ok(()) => true,
+            Err(error) => {
+                self.set_status_message(format!("Could not read all of {}: {} ", self.open_document.path, error));
+                false
             }
         }
     }
 
+    // Runs scan over the document's lines, giving back the (line, column) it stopped at, if any
+    // Lines of a lazily loaded document that haven't been read look blank, so if the scan ends outside the lines read
+    // (or finds nothing) it has passed some, and the whole document is read and scanned again
+    fn scan_lines(&mut self, scan: impl Fn(&[String]) -> Option<(usize, usize)>) -> Option<(usize, usize)> {
+        let found = scan(&self.open_document.lines);
+        if self.open_document.is_lazy() && !found.is_some_and(|(line, _)| self.open_document.is_loaded(line)) && self.materialize() {
+            return scan(&self.open_document.lines);
+        }
+        found
+    }
+
+    // Makes the next draw rewrite every row, for when something else has drawn over the document
+    fn redraw_all(&mut self) {
+        self.drawn_rows.clear();
+    }
+
     // Essentially renders the editor
+    // Only rows that differ from what was drawn last time are rewritten, so nothing flickers and slow terminals get little to do
     pub fn draw(&mut self) -> Result<(), std::io::Error> {
-        self.terminal.clear(); // Clear the screen
         self.terminal.set_cursor_visibility(false); // Hide cursor before drawing
-        self.terminal.zero_cursor_position(); // Zero out the cursor position
+        self.load_visible_lines();
+
+        // Lay out the gutter -- line numbers wide enough for the last line and a space, then the change bar
+        let changes = if self.change_gutter { self.open_document.line_changes() } else { vec![] };
+        let number_width = if self.line_numbers { self.open_document.lines.len().to_string().len() } else { 0 };
+        self.terminal.x_offset = if self.line_numbers { number_width + 1 } else { 0 } + if self.change_gutter { 1 } else { 0 };
+        self.scroll_to_cursor_column(); // Typing and jumps can take the cursor past either edge too
+
+        // Work out the word to highlight once per frame, so a batch of movement keys only looks it up once
+        let word = if self.config.highlight_word { self.word_under_cursor() } else { None };
+        let selection = self.selection();
+        let syntax = self.config.syntax_highlighting.then(|| Syntax::for_document(&self.open_document));
 
         // Draw the editor
-        for mut row_index in 0..self.terminal.height - 1 {
-            row_index = row_index + self.scroll_position; // Adjust for scroll position
-            // Write line if it exists at row index otherwise draw a tilde
-            if self.open_document.lines.len() > row_index {
-                println!("{}\r", self.open_document.lines[row_index].replace("\n", ""));
-            } else {
-                println!("{}{}", "~", "\r");
-            }
-            // Draw welcome message if editor is empty
-            if self.open_document.lines.len() <= 1 && self.open_document.lines[0].len() == 0 {
-                if row_index == (self.terminal.height / 2) - 2 { // The adjustment up 2 is arbitrary but it looks good
-                    let message = format!("BIM (Bad vIM) - version {}", env!("CARGO_PKG_VERSION")); // Welcome message
-                    let mut padding = self.terminal.width - message.len(); // Calculate padding
-                    if padding > 0 {
-                        padding /= 2; // Divide by 2 to center
-                        for _ in 0..padding {
-                            print!(" "); // Print padding
+        let rows: Vec<VisibleRow> = self.visible_rows().collect();
+        self.drawn_rows.resize(rows.len(), None);
+        for row in rows {
+            // Lay out the visible part of the line if the row shows one otherwise the empty line marker
+            let drawn = if let Some(line_index) = row.line {
+                let line = self.open_document.line(line_index).unwrap_or_default(); // Line to draw
+                let cells = display_cells(line, self.tab_width());
+                let mut visible = cells[row.columns.clone()].to_vec(); // Part of the line that fits on screen
+                let mut marker = ""; // Continuation marker drawn after the text
+                if self.line_markers && cells.len() > row.columns.end { // Line continues past the right edge
+                    visible.pop();
+                    marker = ">"; // Mark the hidden content in the last column
+                }
+                // A wide character cut in half by either edge is drawn as a space, so nothing spills past the row's columns
+                if cells.get(row.columns.start + visible.len()).is_some_and(|(c, _)| c.is_none()) {
+                    if let Some(last) = visible.last_mut() {
+                        last.0 = Some(' ');
+                    }
+                }
+                let mut left_marker = ""; // Marker drawn before the text
+                if self.line_markers && row.columns.start > 0 { // Line has content scrolled off the left edge
+                    if !visible.is_empty() {
+                        visible.remove(0);
+                    }
+                    left_marker = "<"; // Mark the hidden content in the first column
+                }
+                if let Some(first) = visible.first_mut().filter(|(c, _)| c.is_none()) {
+                    first.0 = Some(' ');
+                }
+                visible.retain(|(c, _)| c.is_some()); // One entry per character written from here on
+                // Color the syntax and paint the selection blue, trailing spaces and tabs red and the word under the cursor yellow without touching the stored line
+                let trailing_start = line.trim_end_matches([' ', '\t']).chars().count(); // Character index where trailing whitespace begins
+                let word_columns: Vec<Range<usize>> = match &word { // Character ranges of the word's occurrences
+                    Some(word) => word_occurrences(line, word).map(|start| start..start + word.chars().count()).collect(),
+                    None => vec![],
+                };
+                let selected = |index: usize| selection.is_some_and(|(start, end)| (start..end).contains(&(line_index, index)));
+                let spans = syntax.as_ref().map_or(vec![], |syntax| syntax.highlight(line));
+                let foregrounds: Vec<Color> = visible.iter()
+                    .map(|(_, index)| match spans.iter().find(|span| span.columns.contains(index)) {
+                        Some(span) if !selected(*index) => span.token.color(), // Selected text stays plain so it shows on blue
+                        _ => Color::Reset,
+                    })
+                    .collect();
+                let backgrounds: Vec<Color> = visible.iter()
+                    .map(|(_, index)| {
+                        if selected(*index) {
+                            Color::Blue
+                        } else if word_columns.iter().any(|columns| columns.contains(index)) {
+                            Color::Yellow
+                        } else if self.config.highlight_trailing_whitespace && *index >= trailing_start {
+                            Color::Red
+                        } else {
+                            Color::Reset
+                        }
+                    })
+                    .collect();
+                DrawnRow::Line {
+                    number: if self.line_numbers { format!("{:>width$} ", line_index + 1, width = number_width) } else { String::new() },
+                    change: match changes.get(line_index) { // Bar in the gutter for lines changed since load or the last save
+                        Some(LineChange::Modified) => Some(Color::Yellow),
+                        Some(LineChange::Added) => Some(Color::Green),
+                        _ => None,
+                    },
+                    left_marker,
+                    text: visible.iter().filter_map(|(c, _)| *c).collect(),
+                    foregrounds,
+                    backgrounds,
+                    marker,
+                }
+            } else { // Past the end of the document
+                DrawnRow::Empty(self.config.empty_line_marker.clone())
+            };
+            if self.drawn_rows[row.screen_row].as_ref() == Some(&drawn) { // Already on screen
+                continue;
+            }
+            self.terminal.goto(0, row.screen_row);
+            self.terminal.clear_line();
+            match &drawn {
+                DrawnRow::Line { number, change, left_marker, text, foregrounds, backgrounds, marker } => {
+                    self.terminal.write(number);
+                    if self.change_gutter {
+                        match change {
+                            Some(color) => self.write_colored("▌", *color),
+                            None => self.terminal.write(" "),
                         }
                     }
-                    print!("{}\r", message); // Print welcome message
+                    self.terminal.write(left_marker);
+                    self.write_with_colors(text, foregrounds, backgrounds);
+                    self.terminal.write(marker);
                 }
+                DrawnRow::Empty(marker) => self.write_colored(marker, self.config.empty_line_color),
             }
+            self.drawn_rows[row.screen_row] = Some(drawn);
         }
-        // Print bottom status bar
-        print!("{}{}{}{}",
-            termion::color::Bg(termion::color::White),
-            self.status_bar, "\r",
-            termion::color::Bg(termion::color::Reset));
-        
-        self.terminal.set_cursor_position(self.terminal.get_cursor_position()); // Undo cursor zeroing
+        // Print bottom status bar, inverted while the bell is flashing
+        self.terminal.goto(0, self.terminal.height.saturating_sub(1));
+        self.terminal.clear_line();
+        self.terminal.set_background(Color::White);
+        self.terminal.set_inverted(self.bell);
+        let width = self.terminal.width;
+        let mut status: String = self.status_bar.chars().take(width).collect(); // Status bar text, cut to fit so it doesn't wrap
+        if self.config.status_clock && width >= CLOCK_WIDTH { // Clock at the right edge
+            status = status.chars().take(width - CLOCK_WIDTH).collect();
+            let padding = width - CLOCK_WIDTH - status.chars().count();
+            status.push_str(&" ".repeat(padding));
+            status.push_str(&clock());
+        }
+        self.terminal.write(&format!("{}\r", status));
+        self.terminal.set_inverted(false);
+        self.terminal.set_background(Color::Reset);
+
+        // Draw the start page over a brand-new empty document
+        if self.on_start_page() {
+            self.draw_start_page();
+            self.redraw_all(); // The page is drawn over the rows
+        }
+
+        let position = self.terminal.get_cursor_position(); // Undo cursor zeroing, at the cursor's screen column less the horizontal scroll
+        self.terminal.goto(self.cursor_display_column().saturating_sub(self.h_scroll) + self.terminal.x_offset, position.y);
         self.terminal.set_cursor_visibility(true); // Show cursor after drawing
         self.terminal.flush() // Flush the terminal
     }
 
+    // Is the start page showing? Only while the document is empty and untouched
+    fn on_start_page(&self) -> bool {
+        !self.dirty && self.open_document.lines.len() == 1 && self.open_document.lines[0].is_empty()
+    }
+
+    // Draws the version, the main keys and the recent files as a block in the middle of the screen
+    fn draw_start_page(&mut self) {
+        let mut page = vec![
+            format!("BIM (Bad vIM) - version {}", env!("CARGO_PKG_VERSION")),
+            "".to_string(),
+            "ESC     Options (save, quit, ...)".to_string(),
+            "Ctrl+P  Find a file to open".to_string(),
+            "Ctrl+F  Search the document (Ctrl+E to replace)".to_string(),
+            "Ctrl+G  Go to a line (Ctrl+O to the matching bracket)".to_string(),
+            "Ctrl+B  Select (Ctrl+A all, Ctrl+C copy, Ctrl+X cut, Ctrl+U paste)".to_string(),
+            "Ctrl+R  Repeat the last edit".to_string(),
+            "Ctrl+Z  Undo (Ctrl+Y to redo)".to_string(),
+        ];
+        let recent = &self.recent_files[..self.recent_files.len().min(START_PAGE_RECENT)];
+        if !recent.is_empty() {
+            page.push("".to_string());
+            page.push("Recent files (Up/Down to pick, Enter to open)".to_string());
+        }
+        let first_file = page.len(); // Row of the page the recent files start on
+        page.extend(recent.iter().cloned());
+
+        let rows = self.terminal.height.saturating_sub(1); // Rows above the status bar
+        let top = rows.saturating_sub(page.len()) / 2; // Center the block vertically
+        let block_width = page.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        let left = self.terminal.width.saturating_sub(block_width) / 2; // Center the block horizontally
+        for (index, line) in page.iter().enumerate().take(rows) {
+            let line: String = line.chars().take(self.terminal.width - left).collect();
+            self.terminal.goto(left, top + index);
+            self.terminal.set_inverted(self.start_page_selection.map(|selected| first_file + selected) == Some(index));
+            self.terminal.write(&line);
+            self.terminal.set_inverted(false);
+        }
+    }
+
+    // Handles Up, Down and Enter for picking a recent file on the start page
+    // Returns whether the key was used, anything else drops the highlight and is handled as usual
+    fn pick_recent_file(&mut self, key: Key) -> bool {
+        let count = self.recent_files.len().min(START_PAGE_RECENT);
+        match (key, self.start_page_selection) {
+            (Key::Down, None) if count > 0 => self.start_page_selection = Some(0),
+            (Key::Down, Some(selected)) => self.start_page_selection = Some((selected + 1).min(count - 1)),
+            (Key::Up, Some(selected)) => self.start_page_selection = Some(selected.saturating_sub(1)),
+            (Key::Char('\n'), Some(selected)) => { // Enter opens the highlighted file
+                let path = self.recent_files[selected].clone();
+                self.start_page_selection = None;
+                self.open_file(&path);
+            }
+            _ => {
+                self.start_page_selection = None;
+                return false;
+            }
+        }
+        true
+    }
+
     // Handles all keystrokes in editor mode
-    pub fn process_input(&mut self) -> Result<(), std::io::Error> {
-        let key = read_key()?; // Read keystroke
+    pub fn process_input(&mut self, key: Key) -> Result<(), std::io::Error> {
+        self.moved_last = false;
+        if key != Key::Char('\n') { // Only an immediate Enter undoes a comment continuation
+            self.continued_comment = None;
+        }
+        if self.quote_next { // Key after Ctrl+V goes into the document as it is
+            self.quote_next = false;
+            self.status_message = None; // The "Insert literal" prompt
+            if let Some(c) = literal_char(key) {
+                self.perform(Action::Insert(c.to_string()));
+            }
+            return Ok(());
+        }
+        if self.register_next { // Key after Alt+R names the register for the next copy, cut or paste
+            self.register_next = false;
+            self.status_message = None; // The "Register" prompt
+            self.register = match key {
+                Key::Char(c) if c.is_ascii_lowercase() || c.is_ascii_digit() => Some(c),
+                _ => None, // Anything else cancels
+            };
+            return Ok(());
+        }
+        if self.on_start_page() && self.pick_recent_file(key) { // Choosing a file to open instead of editing
+            return Ok(());
+        }
+        if self.read_only && !(self.config.vim_mode && self.normal_mode) && is_editing_key(key) {
+            self.refuse_edit();
+            return Ok(());
+        }
+        let key = if self.config.vim_mode && self.normal_mode { // Vim normal mode, keys are commands
+            match self.normal_command(key)? {
+                Some(key) => key, // Motion, handled like the key it stands for
+                None => return Ok(()),
+            }
+        } else {
+            key
+        };
+        self.moved_last = matches!(key, Key::Up | Key::Down | Key::Left | Key::Right | Key::PageUp | Key::PageDown | Key::Home | Key::End | Key::Alt('b') | Key::Alt('f'));
+        if !matches!(key, Key::Up | Key::Down | Key::PageUp | Key::PageDown) { // Anything but vertical movement sets a new preferred column
+            self.preferred_column = None;
+        }
+        if !self.moved_last && !matches!(key, Key::Ctrl('b') | Key::Ctrl('c') | Key::Ctrl('x') | Key::Alt('x') | Key::Alt('r') | Key::Ctrl('t')) { // Anything but movement and the selection keys drops the selection
+            self.selection_anchor = None;
+        }
+        if !matches!(key, Key::Ctrl('b') | Key::Ctrl('c') | Key::Ctrl('x') | Key::Alt('x') | Key::Ctrl('u') | Key::Alt('u')) { // A chosen register only lasts until the next key
+            self.register = None;
+        }
         match key {
-            Key::Char('\n') => { // Enter key
-                let mut position = self.terminal.get_cursor_position();                                // Current cursor position
-                let mut line = self.open_document.lines[position.y + self.scroll_position].clone();    // Current line
-                let after_cursor = line.split_off(position.x);                                         // All characters after cursor
-                line.truncate(position.x);                                                             // Remove all characters after cursor from current line
-                self.open_document.lines[position.y + self.scroll_position] = line;                    // Update current line
-                self.open_document.lines.insert(position.y + self.scroll_position + 1, after_cursor);  // Insert new line after current line
-                if position.y + 1 > self.terminal.height - 2 { // Attempting to enter past end of screen
-                    self.scroll_position += 1; // Scroll down 1
-                } else {
-                    position.y = position.y.saturating_add(1); // Move cursor down 1
+            Key::Char('\n') => self.perform(Action::Newline), // Enter key
+            Key::Backspace => self.perform(Action::Backspace), // Backspace key
+            Key::Delete => self.perform(Action::Delete), // Delete key
+            Key::Char('\t') if self.config.expand_tabs => { // Spaces up to the next tab stop
+                let width = self.config.tab_width - self.cursor_display_column() % self.config.tab_width;
+                self.perform(Action::Insert(" ".repeat(width)));
+            }
+            Key::Char(c) => self.perform(Action::Insert(c.to_string())), // Any "normal" character
+            Key::Ctrl('t') if self.selection_anchor.is_some() => self.toggle_selection_case(), // Toggle case of the selection on Ctrl+T
+            Key::Ctrl('t') => self.perform(Action::ToggleCase), // Toggle case on Ctrl+T
+            Key::Ctrl('v') => { // Insert the next key literally on Ctrl+V
+                self.quote_next = true;
+                self.set_status_message("Insert literal: ".to_string());
+            }
+            Key::Alt('r') => self.choose_register(), // Name a register for the next copy, cut or paste on Alt+R
+            Key::Ctrl('b') => { // Start or stop selecting on Ctrl+B
+                self.selection_anchor = match self.selection_anchor {
+                    Some(_) => None,
+                    None => Some(self.cursor()),
+                };
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('a') => { // Select the whole document on Ctrl+A
+                if self.materialize() { // The end of the last line has to be read
+                    let last = self.open_document.lines.len() - 1;
+                    self.selection_anchor = Some(terminal::Position::default());
+                    self.move_cursor_to(last, self.open_document.line_len(last));
                 }
-                position.x = 0; // Move cursor to beginning of line
-                self.terminal.set_cursor_position(position); // Update cursor position
-            }
-            Key::Backspace => { // Backspace key
-                let mut position = self.terminal.get_cursor_position();                             // Current cursor position
-                let mut line = self.open_document.lines[position.y + self.scroll_position].clone(); // Current line
-                if position.x > 0 {                                                                 // If cursor is not at beginning of line
-                    line.remove(position.x - 1);                                                    // Remove character before cursor
-                    position.x = position.x.saturating_sub(1);                                      // Move cursor back 1
-                    self.terminal.set_cursor_position(position);                                    // Update cursor position
-                    self.open_document.lines[position.y + self.scroll_position] = line;             // Update current line
-                } else if position.y > 0 || self.scroll_position > 0 {                              // If cursor is at beginning of line and not at beginning of document
-                    let mut prev_line =                                                             // Previous line
-                        self.open_document.lines[(position.y + self.scroll_position) - 1].clone();
-                    if self.scroll_position > 0 && position.y == 0 {                                // If cursor is at beginning of screen and not at beginning of document
-                        self.scroll_position = self.scroll_position.saturating_sub(1);              // Scroll up 1
-                    }
-                    let prev_line_len = prev_line.len().clone();                                    // Cloned length of previous line (used to set position later)
-                    prev_line += &line;                                                             // Append contents of current line to previous line
-                    self.open_document.lines.remove(position.y + self.scroll_position);             // Remove current line
-                    position.y = position.y.saturating_sub(1);                                      // Move cursor up 1
-                    position.x = prev_line_len;                                                     // Move cursor to the cloned length of the line before
-                    self.terminal.set_cursor_position(position);                                    // Update cursor position
-                    self.open_document.lines[position.y + self.scroll_position] = prev_line;        // Update line
-                }
-            }
-            Key::Char(c) => { // Any "normal" character
-                let mut position = self.terminal.get_cursor_position();                              // Current cursor position
-                let mut line = self.open_document.lines[position.y + self.scroll_position].clone();  // Clone current line
-                line.insert(position.x, c);                                                          // Insert character at cursor position
-                self.open_document.lines[position.y + self.scroll_position] = line;                  // Update current line
-                position.x = position.x.saturating_add(1);                                           // Move cursor forward 1
-                self.terminal.set_cursor_position(position);                                         // Update cursor position
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('c') => { // Copy on Ctrl+C
+                self.copy_selection();
+                self.selection_anchor = None;
+            }
+            Key::Ctrl('x') | Key::Alt('x') => { // Cut on Ctrl+X, or on Alt+X without asking first however much is selected
+                if key == Key::Alt('x') || self.confirm_large_cut()? {
+                    self.copy_selection();
+                    self.delete_selection();
+                }
+                self.insert_run_open = false;
+            }
+            Key::Alt('u') => { // Paste on Alt+U, moving the pasted lines to the cursor line's indentation
+                self.insert_run_open = false;
+                let text = self.stored_text();
+                if !text.is_empty() {
+                    let line = self.open_document.line(self.cursor().y).unwrap_or_default();
+                    let indent: String = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+                    self.perform(Action::Insert(reindent(&text, &indent)));
+                }
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('u') => { // Paste on Ctrl+U
+                self.insert_run_open = false; // Undone on its own rather than with typing before it
+                let text = self.stored_text();
+                if !text.is_empty() {
+                    self.perform(Action::Insert(text));
+                }
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('p') => { // Fuzzy find a file to open on Ctrl+P
+                self.find_file()?;
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('f') => { // Search on Ctrl+F
+                self.start_search();
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('e') => { // Find and replace on Ctrl+E
+                self.find_and_replace()?;
+                self.insert_run_open = false;
+            }
+            Key::Alt('n') => { // Next open file on Alt+N or Ctrl+PageDown
+                self.switch_buffer((self.active_buffer + 1) % (self.buffers.len() + 1));
+                self.insert_run_open = false;
+            }
+            Key::Alt('p') => { // Previous open file on Alt+P or Ctrl+PageUp
+                self.switch_buffer((self.active_buffer + self.buffers.len()) % (self.buffers.len() + 1));
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('6') => { // Back to the file open before this one on Ctrl+^, as in vim
+                self.switch_buffer(self.last_buffer);
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('o') => { // Jump to the matching bracket on Ctrl+O
+                self.jump_to_matching_bracket();
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('g') => { // Go to a line on Ctrl+G
+                if let Some(line) = self.prompt("Go to line: ")? {
+                    self.go_to_line(&line);
+                }
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('z') => { // Undo on Ctrl+Z
+                self.undo();
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('y') => { // Redo on Ctrl+Y
+                self.redo();
+                self.insert_run_open = false;
+            }
+            Key::Ctrl('r') => { // Repeat the last action on Ctrl+R
+                self.insert_run_open = false; // Not undone together with the run before it either
+                if let Some(action) = self.last_action.clone() {
+                    self.apply_action(&action);
+                }
+                self.insert_run_open = false; // A repeat never extends the insert run it replayed
             }
             // Cursor movement keys
             Key::Up
@@ -175,81 +904,3245 @@ impl Editor {
             | Key::PageDown
             | Key::PageUp
             | Key::Home
-            | Key::End => self.arrow_move(key),
-            Key::Esc => self.options_mode = true, // Enter options mode on ESC
+            | Key::End
+            | Key::Alt('b') // Ctrl+Left comes through as Alt+b
+            | Key::Alt('f') => { // And Ctrl+Right as Alt+f
+                self.arrow_move(key);
+                self.insert_run_open = false; // Moving the cursor ends the current insert run
+            }
+            Key::Esc if self.config.vim_mode => { // Back to normal mode on ESC in vim mode
+                self.normal_mode = true;
+                self.insert_run_open = false;
+            }
+            Key::Esc => { // Enter options mode on ESC
+                self.open_options();
+                self.insert_run_open = false;
+            }
             _ => (), // Ignore all other keys
         }
         Ok(())
     }
 
-    // Handles all keystrokes in options mode
-    pub fn process_options(&mut self) -> Result<(), std::io::Error> {
-        let key = read_key()?; // Read keystroke
+    // Asks for text to find and what to replace it with, then goes through the matches asking about each one
+    fn find_and_replace(&mut self) -> Result<(), std::io::Error> {
+        if !self.materialize() {
+            return Ok(());
+        }
+        let query = match self.prompt("Replace: ")? {
+            Some(query) if !query.is_empty() => query,
+            _ => return Ok(()),
+        };
+        let Some(replacement) = self.prompt(&format!("Replace {} with: ", query))? else {
+            return Ok(());
+        };
+        let (found, replaced) = self.replace_matches(&query, &replacement, |editor| {
+            editor.status_bar = "Replace? y: Yes / n: No / a: All / ESC: Stop ".to_string();
+            editor.draw()?;
+            editor.show_status_cursor()?;
+            editor.read_key()
+        })?;
+        self.set_status_message(match found {
+            0 => format!("No matches for {} ", query),
+            _ => format!("Replaced {} of {} matches ", replaced, found),
+        });
+        Ok(())
+    }
+
+    // Goes through the matches of query from the cursor on, wrapping round to stop where it started, with ask deciding on each
+    // ask answers y to replace the match, n to skip it, a to replace it and all the rest, and anything else to stop
+    // Returns how many matches were looked at and how many of them were replaced, which are undone together
+    fn replace_matches(
+        &mut self,
+        query: &str,
+        replacement: &str,
+        mut ask: impl FnMut(&mut Self) -> Result<Key, std::io::Error>,
+    ) -> Result<(usize, usize), std::io::Error> {
+        let cursor = self.cursor();
+        let mut origin = (cursor.y, cursor.x); // Where going round stops
+        let mut from = origin; // Where to look for the next match
+        let mut wrapped = false; // Gone round past the end of the document?
+        let mut all = false; // Replace the rest without asking?
+        let (mut found, mut replaced) = (0, 0);
+        let (query_len, replacement_len) = (query.chars().count(), replacement.chars().count());
+        while let Some(position) = self.open_document.find(query, from.0, from.1) {
+            if position < from { // Gone round past the end
+                if wrapped {
+                    break;
+                }
+                wrapped = true;
+            }
+            if wrapped && position >= origin { // Back where it started
+                break;
+            }
+            found += 1;
+            self.move_cursor_to(position.0, position.1);
+            match if all { Key::Char('a') } else { ask(self)? } {
+                key @ (Key::Char('y') | Key::Char('a')) => {
+                    all = key == Key::Char('a');
+                    self.record_edit(position.0..position.0 + 1, replaced > 0, |editor| {
+                        let line = &mut editor.open_document.lines[position.0];
+                        let start = document::byte_index(line, position.1).unwrap_or(line.len());
+                        line.replace_range(start..start + query.len(), replacement);
+                    });
+                    replaced += 1;
+                    if wrapped && position.0 == origin.0 { // Text before where it started changed length
+                        origin.1 = (origin.1 + replacement_len).saturating_sub(query_len);
+                    }
+                    from = (position.0, position.1 + replacement_len); // Past the replacement, which may contain the query
+                }
+                Key::Char('n') => from = (position.0, position.1 + query_len),
+                _ => {
+                    found -= 1; // Stopped before deciding on this one
+                    break;
+                }
+            }
+        }
+        if replaced > 0 {
+            self.dirty = true;
+            self.document_changed();
+        }
+        Ok((found, replaced))
+    }
+
+    // Enters search mode with an empty query
+    fn start_search(&mut self) {
+        if !self.materialize() {
+            return;
+        }
+        self.search_query = Some(String::new());
+        self.search_origin = self.view();
+        self.search_failed = false;
+    }
+
+    // Handles a key in search mode -- typing refines the query and jumps to the first match from where the search started,
+    // Enter goes on to the next match and ESC goes back to where the search started
+    // Any other key ends the search, leaving the cursor on the match, and is then handled as usual
+    fn process_search(&mut self, key: Key) -> Result<(), std::io::Error> {
+        let query = self.search_query.get_or_insert_with(String::new);
         match key {
-            Key::Char('q') => self.running = false, // Exit program on q
-            Key::Char('s') => { // Save on s
-                self.open_document.save();
-                self.options_mode = false;
+            Key::Char('\n') if query.is_empty() => self.search_query = None, // Nothing to search for, just leave
+            Key::Char('\n') => { // Next match after the cursor
+                let cursor = self.cursor();
+                self.search_from(cursor.y, cursor.x + 1);
             }
-            Key::Char('a') => { // Save and exit on a
-                self.open_document.save();
-                self.options_mode = false;
-                self.running = false;
+            Key::Char(c) => {
+                query.push(c);
+                self.search_from_origin();
+            }
+            Key::Backspace => {
+                query.pop();
+                self.search_from_origin();
+            }
+            Key::Esc => { // Cancel
+                self.search_query = None;
+                self.restore_view(self.search_origin);
+            }
+            _ => {
+                self.search_query = None;
+                return self.process_input(key);
             }
-            Key::Esc => self.options_mode = false, // Exit options mode on ESC
-            _ => (), // Ignore all other keys
         }
         Ok(())
     }
 
-    // Takes a termion key and moves cursor accordingly
-    pub fn arrow_move(&mut self, key: Key) {
-        let mut position = self.terminal.get_cursor_position();
+    // Searches again for the whole query from where the search started
+    fn search_from_origin(&mut self) {
+        let origin = self.search_origin;
+        self.restore_view(origin);
+        self.search_from(origin.cursor.y + origin.scroll_position, origin.cursor.x);
+    }
+
+    // Moves the cursor to the next match of the search query at or after column of line, wrapping round past the end
+    fn search_from(&mut self, line: usize, column: usize) {
+        let query = self.search_query.as_deref().unwrap_or_default();
+        let found = self.open_document.find(query, line, column);
+        self.search_failed = found.is_none() && !query.is_empty();
+        if let Some((line, column)) = found {
+            self.move_cursor_to(line, column);
+            if self.config.search_centers_match {
+                self.center_line(line);
+            }
+        }
+    }
+
+    // Scrolls line to the middle of the screen unless it is already in the middle third, without scrolling past either end of the document
+    fn center_line(&mut self, line: usize) {
+        let rows = self.terminal.height.saturating_sub(1).max(1); // Rows available for text
+        let row = line.saturating_sub(self.scroll_position);
+        if line < self.scroll_position || row < rows / 3 || row >= rows - rows / 3 {
+            let last_scroll = self.open_document.lines.len().saturating_sub(rows); // Scrolled to show the last line at the bottom
+            self.scroll_position = line.saturating_sub(rows / 2).min(last_scroll);
+            let mut position = self.terminal.get_cursor_position();
+            position.y = line - self.scroll_position;
+            self.terminal.set_cursor_position(position);
+        }
+    }
+
+    // Start and end of the selection as (line, column), in document order, None when nothing is selected
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.selection_anchor?;
+        let cursor = self.cursor();
+        let (anchor, cursor) = ((anchor.y, anchor.x), (cursor.y, cursor.x));
+        Some((anchor.min(cursor), anchor.max(cursor)))
+    }
+
+    // Copies the selected text to the clipboard
+    fn copy_selection(&mut self) {
+        match self.selection() {
+            Some((start, end)) => self.store_text(self.open_document.text_between(start, end)),
+            None => self.set_status_message("Nothing selected (Ctrl+B to start selecting) ".to_string()),
+        }
+    }
+
+    // Asks before cutting a selection spanning more than confirm_cut_lines lines, returning whether to go ahead
+    fn confirm_large_cut(&mut self) -> Result<bool, std::io::Error> {
+        let lines = self.selection().map_or(0, |(start, end)| end.0 - start.0 + 1);
+        let limit = self.config.confirm_cut_lines;
+        if limit == 0 || lines <= limit {
+            return Ok(true);
+        }
+        self.confirm(&format!("Cut {} lines? (y/n, Alt+X cuts without asking) ", lines))
+    }
+
+    // Deletes the selected text, leaving the cursor where it started, and stops selecting
+    fn delete_selection(&mut self) {
+        let Some((start, end)) = self.selection() else {
+            return;
+        };
+        self.selection_anchor = None;
+        if start == end {
+            return;
+        }
+        self.record_edit(start.0..end.0 + 1, false, |editor| {
+            editor.open_document.remove_between(start, end);
+            editor.move_cursor_to(start.0, start.1); // Scrolls back up if the selection started above the screen
+        });
+        self.dirty = true;
+        self.document_changed();
+    }
+
+    // Puts the cursor at column of line, scrolling only as far as needed to show it
+    fn move_cursor_to(&mut self, line: usize, column: usize) {
+        self.scroll_position = self.scroll_position.min(line);
+        self.terminal.set_cursor_position(terminal::Position { x: column, y: line - self.scroll_position });
+        self.clamp_viewport();
+    }
+
+    // Handles a left click on screen cell x, y (0-based) -- one click moves the cursor there,
+    // a double click selects the word under it (words as Ctrl+Left and Ctrl+Right see them) and a triple click the whole line
+    pub fn click(&mut self, x: usize, y: usize) {
+        if self.options_mode || self.search_query.is_some() { // Only the document takes clicks
+            return;
+        }
+        let Some(line) = self.visible_rows().find(|row| row.screen_row == y).and_then(|row| row.line) else {
+            return; // Past the end of the document or on the status bar
+        };
+        let column = self.column_at_display(line, x.saturating_sub(self.terminal.x_offset) + self.h_scroll);
+        let count = match self.last_click {
+            Some((time, at, count)) if at == (column, line) && time.elapsed().as_millis() < DOUBLE_CLICK_MS as u128 => count % 3 + 1,
+            _ => 1,
+        };
+        self.last_click = Some((std::time::Instant::now(), (column, line), count));
+        self.preferred_column = None;
+        self.insert_run_open = false;
+        let text: Vec<char> = self.open_document.line(line).unwrap_or_default().chars().collect();
+        match count {
+            2 if text.get(column).is_some_and(|c| is_word_char(*c)) => { // Double click on a word
+                let start = column - text[..column].iter().rev().take_while(|c| is_word_char(**c)).count();
+                let end = column + text[column..].iter().take_while(|c| is_word_char(**c)).count();
+                self.selection_anchor = Some(terminal::Position { x: start, y: line });
+                self.move_cursor_to(line, end);
+            }
+            3 => { // Triple click
+                self.selection_anchor = Some(terminal::Position { x: 0, y: line });
+                self.move_cursor_to(line, text.len());
+            }
+            _ => {
+                self.selection_anchor = None;
+                self.move_cursor_to(line, column);
+            }
+        }
+    }
+
+    // Handles a key in vim normal mode
+    // Motions come back as the key to handle them with, every other key is dealt with here and gives None
+    fn normal_command(&mut self, key: Key) -> Result<Option<Key>, std::io::Error> {
+        if let Some(operator) = self.pending_operator.take() { // Second key of d or y
+            self.apply_operator(operator, key);
+            self.register = None; // Used up, or left unused by a cancelled operator
+            return Ok(None);
+        }
         match key {
-            Key::Up => { // Up arrow
-                if position.y > 0 { // If cursor is not at top of screen
-                    position.y = position.y.saturating_sub(1); // Move cursor up 1
-                } else if position.y == 0 && self.scroll_position > 0 { // If cursor is at top of screen and not at top of document
-                    self.scroll_position = self.scroll_position.saturating_sub(1); // Scroll up 1
-                }
-                if position.x > self.open_document.lines[position.y + self.scroll_position].len() { // If cursor is past end of line after moving
-                    position.x = self.open_document.lines[position.y + self.scroll_position].len(); // Move cursor to end of line
-                }
+            Key::Char('x' | 'o' | 'u' | 'd' | 'p') if self.read_only => self.refuse_edit(), // Commands that change the document
+            Key::Char('h') => return Ok(Some(Key::Left)),
+            Key::Char('j') => return Ok(Some(Key::Down)),
+            Key::Char('k') => return Ok(Some(Key::Up)),
+            Key::Char('l') => return Ok(Some(Key::Right)),
+            Key::Char('0') => return Ok(Some(Key::Home)),
+            Key::Char('%') => return Ok(Some(Key::Ctrl('o'))), // Matching bracket
+            Key::Char('$') => return Ok(Some(Key::End)),
+            Key::Char('w') => return Ok(Some(Key::Alt('f'))), // Next word
+            Key::Char('b') => return Ok(Some(Key::Alt('b'))), // Previous word
+            Key::Char('i') => self.normal_mode = false, // Insert before the cursor
+            Key::Char('a') => { // Append after the cursor
+                self.arrow_move(Key::Right);
+                self.normal_mode = false;
             }
-            Key::Down => { // Down arrow
-                let is_at_end_of_document = (position.y + self.scroll_position + 1) == self.open_document.lines.len(); // If cursor is at end of document
-                if !is_at_end_of_document && position.y < self.terminal.height.saturating_sub(2) { // If cursor is not at bottom of screen and not at end of document
-                    position.y = position.y.saturating_add(1); // Move cursor down 1
-                    if position.x > self.open_document.lines[position.y + self.scroll_position].len() { // If cursor is past end of line after moving
-                        position.x = self.open_document.lines[position.y + self.scroll_position].len(); // Move cursor to end of line
-                    }
-                } else if !is_at_end_of_document && position.y == self.terminal.height.saturating_sub(2) { // If cursor is at bottom of screen and not at end of document
-                    self.scroll_position = self.scroll_position.saturating_add(1); // Scroll down 1
-                }
+            Key::Char('A') => { // Append at the end of the line
+                self.arrow_move(Key::End);
+                self.normal_mode = false;
+            }
+            Key::Char('o') => { // Open a new line below
+                self.arrow_move(Key::End);
+                self.perform(Action::Newline);
+                self.normal_mode = false;
             }
-            Key::Left => { // Left arrow
-                if position.x > 0 { // If cursor is not at beginning of line
-                    position.x = position.x.saturating_sub(1); // Move cursor left 1
+            Key::Char('x') => { // Delete the character under the cursor
+                let position = self.cursor();
+                if position.x < self.open_document.line_len(position.y) {
+                    self.arrow_move(Key::Right);
+                    self.perform(Action::Backspace);
                 }
             }
-            Key::Right => { // Right arrow
-                if position.x < self.open_document.lines[position.y + self.scroll_position].len() { // If cursor is not at end of line
-                    position.x = position.x.saturating_add(1); // Move cursor right 1
+            Key::Char(operator @ ('d' | 'y')) => self.pending_operator = Some(operator), // Delete or yank, with the motion to come
+            Key::Char('p') => self.put(), // Paste after the cursor
+            Key::Char('"') => { // Register for the next d, y or p
+                self.choose_register();
+                return Ok(None);
+            }
+            Key::Char('u') => self.undo(), // Undo
+            Key::Char('/') => self.start_search(), // Search
+            Key::Char(':') => { // Ex command
+                if let Some(command) = self.prompt(":")? {
+                    self.ex_command(command.trim())?;
                 }
             }
-            Key::Home => position.x = 0, // Home key moves cursor to beginning of line
-            Key::End => position.x = self.open_document.lines[position.y + self.scroll_position].len(), // End key moves cursor to end of line
-            _ => (), // Ignore all other keys
+            Key::Esc => self.open_options(), // Options menu on ESC
+            Key::Char(_) => (), // Other characters do nothing rather than being typed
+            _ if self.read_only && is_editing_key(key) => self.refuse_edit(), // Delete, Ctrl+T and the like
+            _ => return Ok(Some(key)), // Arrows, Ctrl keys and the rest work as in insert mode
         }
-        self.terminal.set_cursor_position(position); // Update cursor position
+        if self.pending_operator.is_none() { // A chosen register only lasts until the next command
+            self.register = None;
+        }
+        self.insert_run_open = false;
+        Ok(None)
     }
-}
 
-// Reads a termion key from stdin
-fn read_key() -> Result<Key, std::io::Error> {
-    loop {
-        if let Some(key) = io::stdin().lock().keys().next() {
-            return key;
+    // Deletes (d) or yanks (y) to the clipboard from the cursor to where motion moves it, or the whole line when motion repeats the operator
+    // Other keys cancel the operator
+    fn apply_operator(&mut self, operator: char, motion: Key) {
+        let start = self.cursor();
+        let end = match motion {
+            Key::Char(c) if c == operator => { // dd and yy work on the whole line, which pastes as a line of its own
+                let (line, count) = (start.y, self.open_document.lines.len());
+                let text = self.open_document.line(line).unwrap_or_default().to_string();
+                if operator == 'd' {
+                    // Take the line's newline with it, or the one before it for the last line
+                    let (from, to) = match (line + 1 < count, line > 0) {
+                        (true, _) => ((line, 0), (line + 1, 0)),
+                        (false, true) => ((line - 1, self.open_document.line_len(line - 1)), (line, text.chars().count())),
+                        (false, false) => ((line, 0), (line, text.chars().count())),
+                    };
+                    self.move_cursor_to(from.0, from.1);
+                    self.selection_anchor = Some(self.cursor());
+                    self.move_cursor_to(to.0, to.1);
+                    self.delete_selection();
+                    self.move_cursor_to(self.cursor().y, 0);
+                }
+                self.store_text(text + "\n");
+                return;
+            }
+            Key::Char('w') => Key::Alt('f'),
+            Key::Char('b') => Key::Alt('b'),
+            Key::Char('0') => Key::Home,
+            Key::Char('$') => Key::End,
+            _ => return,
+        };
+        self.arrow_move(end);
+        if (self.cursor().x, self.cursor().y) == (start.x, start.y) { // Nothing to take, so the clipboard keeps what it had
+            return;
+        }
+        if self.cursor().y > start.y { // dw on the last word stops at the end of its line, as in vim
+            self.move_cursor_to(start.y, self.open_document.line_len(start.y));
         }
+        self.selection_anchor = Some(start);
+        self.copy_selection();
+        if operator == 'd' {
+            self.delete_selection();
+        } else {
+            let (line, column) = self.selection().map_or((start.y, start.x), |(start, _)| start);
+            self.selection_anchor = None;
+            self.move_cursor_to(line, column); // Yanking leaves the cursor at the start of the text
+        }
+    }
+
+    // Pastes the clipboard after the cursor, or below the current line when it holds whole lines from dd or yy
+    fn put(&mut self) {
+        let line = self.cursor().y;
+        let text = self.stored_text();
+        if let Some(lines) = text.strip_suffix('\n') {
+            let text = format!("\n{}", lines);
+            self.arrow_move(Key::End);
+            self.perform(Action::Insert(text));
+            self.move_cursor_to(line + 1, 0);
+        } else if !text.is_empty() {
+            if self.cursor().x < self.open_document.line_len(line) {
+                self.arrow_move(Key::Right);
+            }
+            self.perform(Action::Insert(text));
+        }
+    }
+
+    // Asks for the register the next copy, cut or paste uses
+    fn choose_register(&mut self) {
+        self.register_next = true;
+        self.set_status_message("Register (a-z, 0-9): ".to_string());
+    }
+
+    // Puts copied or cut text in the chosen register, or the clipboard if none was chosen
+    fn store_text(&mut self, text: String) {
+        match self.register.take() {
+            Some(register) => {
+                self.registers.insert(register, text.split('\n').map(str::to_string).collect());
+            }
+            None => self.clipboard = text,
+        }
+    }
+
+    // Text to paste from the chosen register, or the clipboard if none was chosen
+    fn stored_text(&mut self) -> String {
+        match self.register.take() {
+            Some(register) => self.registers.get(&register).map(|lines| lines.join("\n")).unwrap_or_default(),
+            None => self.clipboard.clone(),
+        }
+    }
+
+    // Runs a vim ex command typed after :
+    fn ex_command(&mut self, command: &str) -> Result<(), std::io::Error> {
+        match command {
+            "w" => {
+                self.save_asking_for_name()?;
+            }
+            "q" if self.has_unsaved_changes() => self.set_status_message("No write since last change (add ! to override) ".to_string()),
+            "q" | "q!" => self.running = false,
+            "wq" | "x" => {
+                if self.save_asking_for_name()? { // Stay open if the save failed
+                    self.quit_unless_other_files_unsaved();
+                }
+            }
+            "" => (),
+            _ if command.chars().all(|c| c.is_ascii_digit()) => self.go_to_line(command), // :428 jumps to line 428
+            _ => self.set_status_message(format!("Not an editor command: {} ", command)),
+        }
+        Ok(())
+    }
+
+    // Applies an action typed by the user and records it as the last action
+    // Consecutive inserts are merged into a single run so a repeat re-types the whole run
+    fn perform(&mut self, action: Action) {
+        self.apply_action(&action);
+        match (&mut self.last_action, &action) {
+            (Some(Action::Insert(run)), Action::Insert(text)) if self.insert_run_open => run.push_str(text), // Extend the open run
+            _ => self.last_action = Some(action.clone()), // Otherwise this action becomes the last action
+        }
+        self.insert_run_open = matches!(action, Action::Insert(_)); // Only inserts leave the run open
+    }
+
+    // Applies an action to the open document at the cursor
+    pub fn apply_action(&mut self, action: &Action) {
+        let paused = self.config.undo_coalesce_ms > 0
+            && self.last_insert_time.elapsed() > std::time::Duration::from_millis(self.config.undo_coalesce_ms);
+        let joined = self.insert_run_open && matches!(action, Action::Insert(_)) && !paused; // A run of typing is undone in one go
+        let touched = match action {
+            Action::Align(_) => {
+                let (start, end) = self.paragraph_range();
+                start..end
+            }
+            _ => { // Other actions change the cursor line, joining it with the line either side at most
+                let line = self.cursor().y;
+                line.saturating_sub(1)..line + 2
+            }
+        };
+        self.record_edit(touched, joined, |editor| match action {
+            Action::Insert(text) => {
+                for c in text.chars() { // Insert each character in turn
+                    if c == '\n' || c == '\r' { // Line breaks split the line rather than being stored in it
+                        editor.insert_newline();
+                    } else {
+                        editor.insert_char(c);
+                    }
+                }
+            }
+            Action::Newline => editor.newline(),
+            Action::Backspace => editor.backspace(),
+            Action::Delete => editor.delete(),
+            Action::ToggleCase => editor.toggle_case(),
+            Action::Align(delimiter) => {
+                let (start, end) = editor.paragraph_range();
+                editor.open_document.align(start, end, delimiter);
+            }
+        });
+        if matches!(action, Action::Insert(_)) {
+            self.last_insert_time = std::time::Instant::now();
+        }
+        self.dirty = true; // Any action changes the document
+        self.document_changed();
+    }
+
+    // Makes an edit that can be undone, where edit only changes the lines in touched (adding or removing lines there is fine)
+    // A joined edit is undone and redone together with the one before it
+    fn record_edit(&mut self, touched: Range<usize>, joined: bool, edit: impl FnOnce(&mut Self)) {
+        if !self.materialize() { // Edits need the whole document
+            return;
+        }
+        let lines = &self.open_document.lines;
+        let start = touched.start.min(lines.len());
+        let end = touched.end.min(lines.len());
+        let removed = lines[start..end].to_vec();
+        let untouched_after = lines.len() - end; // Lines after the touched range, which the edit leaves alone
+        let before = self.view();
+        edit(self);
+        self.clamp_viewport(); // Whatever the edit did, the cursor and scroll position stay inside the document
+        let inserted = self.open_document.lines[start..self.open_document.lines.len() - untouched_after].to_vec();
+        if inserted != removed { // Edits that changed nothing, like backspace at the very start, aren't worth undoing
+            let after = self.view();
+            self.history.record(Change { start, removed, inserted, before, after, joined }, self.config.undo_limit);
+        }
+    }
+
+    // Cursor and scroll position, as remembered by undo
+    fn view(&self) -> View {
+        View { cursor: self.terminal.get_cursor_position(), scroll_position: self.scroll_position }
+    }
+
+    // Opens each of paths in its own buffer, showing the first
+    pub fn open_files(&mut self, paths: &[String]) {
+        for path in paths {
+            if !self.open_document.path.is_empty() { // Keep the file already open in the background
+                let buffer = self.take_buffer();
+                self.buffers.insert(self.active_buffer, buffer);
+                self.active_buffer += 1;
+            }
+            self.open_file(path);
+        }
+        if self.open_document.path.is_empty() && !self.buffers.is_empty() { // The last file couldn't be opened
+            let buffer = self.buffers.remove(self.active_buffer - 1);
+            self.active_buffer -= 1;
+            self.put_buffer(buffer);
+        }
+        self.switch_buffer(0);
+        self.last_buffer = 0; // Nothing to go back to yet
+    }
+
+    // Makes the open file at index (counting the open document) the open document, keeping the current one in the background
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.active_buffer {
+            return;
+        }
+        let buffer = self.take_buffer();
+        self.buffers.insert(self.active_buffer, buffer);
+        let buffer = self.buffers.remove(index);
+        self.last_buffer = self.active_buffer;
+        self.active_buffer = index;
+        self.put_buffer(buffer);
+    }
+
+    // Takes the open document and its state out of the editor, leaving an empty untitled document
+    fn take_buffer(&mut self) -> Buffer {
+        let buffer = Buffer {
+            document: std::mem::take(&mut self.open_document),
+            history: std::mem::take(&mut self.history),
+            dirty: self.dirty,
+            view: self.view(),
+            h_scroll: self.h_scroll,
+        };
+        self.dirty = false;
+        self.restore_view(View { cursor: terminal::Position::default(), scroll_position: 0 });
+        self.h_scroll = 0;
+        buffer
+    }
+
+    // Makes buffer the open document, where it was left
+    fn put_buffer(&mut self, buffer: Buffer) {
+        self.open_document = buffer.document;
+        self.history = buffer.history;
+        self.dirty = buffer.dirty;
+        self.h_scroll = buffer.h_scroll;
+        self.restore_view(buffer.view);
+        self.selection_anchor = None;
+        self.preferred_column = None;
+        self.insert_run_open = false;
+        self.document_changed();
+    }
+
+    // Are there unsaved changes in any open file?
+    fn has_unsaved_changes(&self) -> bool {
+        self.dirty || self.buffers.iter().any(|buffer| buffer.dirty)
+    }
+
+    // Quits, unless an open file in the background still has unsaved changes
+    fn quit_unless_other_files_unsaved(&mut self) {
+        match self.buffers.iter().find(|buffer| buffer.dirty) {
+            Some(buffer) => self.set_status_message(format!("{} has unsaved changes too ", buffer.document.path)),
+            None => self.running = false,
+        }
+    }
+
+    // Saves every open file with unsaved changes, returning whether they all saved
+    fn save_all(&mut self) -> bool {
+        let (active, last) = (self.active_buffer, self.last_buffer);
+        let mut saved = true;
+        for index in 0..=self.buffers.len() {
+            self.switch_buffer(index);
+            if self.dirty {
+                saved &= self.save();
+            }
+        }
+        self.switch_buffer(active);
+        self.last_buffer = last; // Saving visited every file, but Ctrl+^ should still go back to the one before
+        saved
+    }
+
+    // Puts the cursor and scroll position back where undo remembered them
+    fn restore_view(&mut self, view: View) {
+        self.scroll_position = view.scroll_position;
+        self.terminal.set_cursor_position(view.cursor);
+        self.clamp_viewport(); // The terminal may have been resized since
+    }
+
+    // Undoes the most recent edit (or run of typing)
+    fn undo(&mut self) {
+        let changes = self.history.undo();
+        let view = match changes.last() {
+            Some(change) => change.before,
+            None => {
+                self.set_status_message("Already at oldest change ".to_string());
+                return;
+            }
+        };
+        for change in changes {
+            change.revert(&mut self.open_document.lines);
+        }
+        self.restore_view(view);
+        self.dirty = !self.history.is_saved(); // Undoing back to the last save leaves nothing to save
+        self.document_changed();
+    }
+
+    // Redoes the most recently undone edit (or run of typing)
+    fn redo(&mut self) {
+        let changes = self.history.redo();
+        let view = match changes.last() {
+            Some(change) => change.after,
+            None => {
+                self.set_status_message("Already at newest change ".to_string());
+                return;
+            }
+        };
+        for change in changes {
+            change.apply(&mut self.open_document.lines);
+        }
+        self.restore_view(view);
+        self.dirty = !self.history.is_saved();
+        self.document_changed();
+    }
+
+    // Range of lines in the paragraph (run of non-blank lines) around the cursor
+    fn paragraph_range(&self) -> (usize, usize) {
+        let lines = &self.open_document.lines;
+        let current = (self.terminal.get_cursor_position().y + self.scroll_position).min(lines.len() - 1); // Current line index, kept inside the document
+        let mut start = current;
+        while start > 0 && !lines[start - 1].trim().is_empty() { // Walk up to the first line of the paragraph
+            start -= 1;
+        }
+        let mut end = current + 1;
+        while end < lines.len() && !lines[end].trim().is_empty() { // Walk down past the last line of the paragraph
+            end += 1;
+        }
+        (start, end)
+    }
+
+    // Toggles the case of the character under the cursor and moves past it
+    // Non-alphabetic characters are left unchanged but the cursor still advances
+    fn toggle_case(&mut self) {
+        let mut position = self.terminal.get_cursor_position(); // Current cursor position
+        let line = match self.open_document.line_mut(position.y + self.scroll_position) {
+            Some(line) => line, // Current line
+            None => return,
+        };
+        let start = document::byte_index(line, position.x).unwrap_or(line.len()); // Byte offset of the cursor
+        let c = match line[start..].chars().next() {
+            Some(c) => c,   // Character under the cursor
+            None => return, // Nothing under the cursor at end of line
+        };
+        let toggled = toggled_case(c);
+        line.replace_range(start..start + c.len_utf8(), &toggled); // Swap in the toggled character
+        position.x += toggled.chars().count();                     // Move cursor past it
+        self.terminal.set_cursor_position(position);               // Update cursor position
+    }
+
+    // Toggles the case of every selected character, leaving the cursor at the start of the selection, and stops selecting
+    fn toggle_selection_case(&mut self) {
+        let Some((start, end)) = self.selection() else {
+            return;
+        };
+        self.selection_anchor = None;
+        self.record_edit(start.0..end.0 + 1, false, |editor| {
+            for index in start.0..=end.0 {
+                let line = &mut editor.open_document.lines[index];
+                let from = if index == start.0 { start.1 } else { 0 };
+                let to = if index == end.0 { end.1 } else { usize::MAX };
+                *line = line.chars().enumerate()
+                    .map(|(column, c)| if (from..to).contains(&column) { toggled_case(c) } else { c.to_string() })
+                    .collect();
+            }
+            editor.move_cursor_to(start.0, start.1);
+        });
+        self.dirty = true;
+        self.document_changed();
+    }
+
+    // Inserts a character at the cursor
+    fn insert_char(&mut self, c: char) {
+        let mut position = self.terminal.get_cursor_position();                                // Current cursor position
+        if self.open_document.insert_char(position.y + self.scroll_position, position.x, c) { // Insert character at cursor position
+            position.x = position.x.saturating_add(1);                                         // Move cursor forward 1
+            self.terminal.set_cursor_position(position);                                       // Update cursor position
+        }
+    }
+
+    // Splits the current line at the cursor for the Enter key, continuing a comment the cursor is in if enabled
+    // Enter again straight after on the untouched continuation takes the prefix back off instead
+    fn newline(&mut self) {
+        let cursor = self.cursor();
+        if let Some(prefix) = self.continued_comment.take() {
+            if self.open_document.line(cursor.y) == Some(prefix.as_str()) && cursor.x == prefix.chars().count() {
+                let indent = prefix.len() - prefix.trim_start().len(); // Keep the indentation
+                self.open_document.lines[cursor.y].truncate(indent);
+                let mut position = self.terminal.get_cursor_position();
+                position.x = prefix[..indent].chars().count();
+                self.terminal.set_cursor_position(position);
+                return;
+            }
+        }
+        let prefix = match self.config.continue_comments.then(|| self.comment_continuation()).flatten() {
+            Some(prefix) => Some(prefix), // Comment prefixes keep the indentation themselves
+            None if self.config.auto_indent => {
+                let line = self.open_document.line(cursor.y).unwrap_or_default();
+                Some(line.chars().take(cursor.x).take_while(|c| *c == ' ' || *c == '\t').collect()) // Only the indentation before the cursor
+            }
+            None => None,
+        };
+        self.insert_newline();
+        if let Some(prefix) = prefix {
+            for c in prefix.chars() {
+                self.insert_char(c);
+            }
+            if !prefix.trim().is_empty() { // Only a comment prefix is removed by a second Enter
+                self.continued_comment = Some(prefix);
+            }
+        }
+    }
+
+    // Prefix that continues the comment the cursor is in onto a new line, None if it isn't in one
+    // Handles line comments (// and #) and the inside of unclosed /* */ blocks, for recognised file types
+    fn comment_continuation(&self) -> Option<String> {
+        let (line_marker, block_comments) = self.open_document.comment_syntax()?;
+        let cursor = self.cursor();
+        let line = self.open_document.line(cursor.y)?;
+        let rest = line.trim_start();
+        let indent = &line[..line.len() - rest.len()];
+        let marker_len = if rest.starts_with(line_marker) { // Line comment, keeping doc comment markers like /// and //!
+            rest.len() - rest.trim_start_matches(|c| line_marker.contains(c) || c == '!').len()
+        } else if block_comments && rest.starts_with("/*") && !rest.contains("*/") { // First line of a block
+            return (cursor.x >= indent.chars().count() + 2).then(|| format!("{} * ", indent));
+        } else if block_comments && (rest == "*" || rest.starts_with("* ")) && !rest.contains("*/") { // Inside a block
+            1
+        } else {
+            return None;
+        };
+        if cursor.x < indent.chars().count() + marker_len { // Splitting before the comment starts (markers are ASCII, so bytes are characters)
+            return None;
+        }
+        let spacing = rest[marker_len..].chars().take_while(|c| *c == ' ').count(); // Spaces after the marker are kept too
+        Some(format!("{}{}", indent, &rest[..marker_len + spacing]))
+    }
+
+    // Splits the current line at the cursor
+    fn insert_newline(&mut self) {
+        let mut position = self.terminal.get_cursor_position();                     // Current cursor position
+        if !self.open_document.split_line(position.y + self.scroll_position, position.x) { // Move everything after the cursor to a new line
+            return;
+        }
+        let last_row = self.terminal.height.saturating_sub(2); // Last row above the status bar
+        if position.y >= last_row { // Attempting to enter past end of screen
+            let jump = self.config.scroll_jump.clamp(1, last_row + 1); // Scroll at least 1 and at most a screen
+            self.scroll_position += jump; // Scroll down so the new line is visible
+            position.y = position.y + 1 - jump; // Cursor moves up by whatever the jump scrolled past the new line
+        } else {
+            position.y = position.y.saturating_add(1); // Move cursor down 1
+        }
+        position.x = 0; // Move cursor to beginning of line
+        self.terminal.set_cursor_position(position); // Update cursor position
+    }
+
+    // Deletes the character before the cursor, joining with the previous line at column 0
+    fn backspace(&mut self) {
+        let mut position = self.terminal.get_cursor_position();                             // Current cursor position
+        if position.x == 0 && position.y + self.scroll_position == 0 {                      // Nothing before the start of the document
+            return;
+        }
+        let index = position.y + self.scroll_position;                                      // Current line index
+        if position.x > 0 {                                                                 // If cursor is not at beginning of line
+            if self.open_document.remove_char(index, position.x - 1).is_some() {            // Remove character before cursor
+                position.x -= 1;                                                            // Move cursor back 1
+                self.terminal.set_cursor_position(position);                                // Update cursor position
+            }
+        } else if let Some(join_column) = self.open_document.join_with_previous(index) {   // If cursor is at beginning of line, append it to the line before
+            if position.y == 0 {                                                            // If cursor is at beginning of screen and not at beginning of document
+                self.scroll_position = self.scroll_position.saturating_sub(1);              // Scroll up 1
+            } else {
+                position.y -= 1;                                                            // Move cursor up 1
+            }
+            position.x = join_column;                                                       // Move cursor to where the lines were joined
+            self.terminal.set_cursor_position(position);                                    // Update cursor position
+        }
+    }
+
+    // Deletes the character under the cursor, joining the next line on at the end of a line
+    // The cursor stays where it is
+    fn delete(&mut self) {
+        let position = self.terminal.get_cursor_position(); // Current cursor position
+        let index = position.y + self.scroll_position;      // Current line index
+        if position.x < self.open_document.line_len(index) { // If cursor is not at end of line
+            self.open_document.remove_char(index, position.x);
+        } else {
+            self.open_document.join_with_previous(index + 1); // Pull the next line up, if there is one
+        }
+    }
+
+    // Handles all keystrokes in options mode
+    pub fn process_options(&mut self, key: Key) -> Result<(), std::io::Error> {
+        if self.quit_pending { // Waiting on quit confirmation
+            self.quit_pending = false;
+            if key == Key::Char('q') { // Second q quits without saving
+                self.running = false;
+            }
+            return Ok(());
+        }
+        if self.read_only && matches!(key, Key::Char('=' | 'n' | 'f' | '!' | '|')) { // Options that change the document
+            self.options_mode = false;
+            self.refuse_edit();
+            return Ok(());
+        }
+        match key {
+            Key::Char('q') if self.has_unsaved_changes() => { // Ask for confirmation before discarding changes
+                self.quit_pending = true;
+                self.bell = true;
+            }
+            Key::Char('q') => self.running = false, // Exit program on q
+            Key::Char('s') => { // Save on s
+                self.options_mode = false;
+                self.save_asking_for_name()?;
+            }
+            Key::Char('a') => { // Save and exit on a
+                self.options_mode = false;
+                if self.save_asking_for_name()? { // Stay open if the save failed
+                    self.quit_unless_other_files_unsaved();
+                }
+            }
+            Key::Char('=') => { // Align a delimiter across the paragraph on =
+                self.options_mode = false;
+                if let Some(delimiter) = self.prompt("Align on: ")? {
+                    self.perform(Action::Align(delimiter));
+                }
+            }
+            Key::Char('c') => { // Jump to a column of the current line on c
+                self.options_mode = false;
+                if let Some(column) = self.prompt("Go to column: ")? {
+                    self.go_to_column(&column);
+                }
+            }
+            Key::Char('n') => { // Insert a character repeated on n
+                self.options_mode = false;
+                self.insert_repeated()?;
+            }
+            Key::Char('f') => { // Trim blank lines at the end of the document on f
+                self.options_mode = false;
+                self.trim_trailing_blank_lines();
+            }
+            Key::Char('d') => { // Discard all changes on d
+                self.options_mode = false;
+                if self.dirty && self.confirm("Discard all changes since the file was last opened or saved? (y/n) ")? {
+                    self.discard_changes();
+                }
+            }
+            Key::Char('!') => { // Insert the output of a shell command on !
+                self.options_mode = false;
+                if let Some(command) = self.prompt("Insert output of: ")? {
+                    match self.run_command(&command, None) {
+                        Ok(output) => self.perform(Action::Insert(output)),
+                        Err(message) => self.set_status_message(message),
+                    }
+                }
+            }
+            Key::Char('|') => { // Filter the document through a shell command on |
+                self.options_mode = false;
+                if let Some(command) = self.prompt("Filter document through: ")? {
+                    self.filter_document(&command);
+                }
+            }
+            Key::Char('w') => { // Write a range of lines to another file on w
+                self.options_mode = false;
+                self.export_lines()?;
+            }
+            Key::Char('r') => { // Reload from disk on r
+                self.options_mode = false;
+                self.reload();
+            }
+            Key::Char('l') => { // Toggle line numbers on l
+                self.line_numbers = !self.line_numbers;
+                self.options_mode = false;
+            }
+            Key::Char('g') => { // Toggle the changed-lines gutter on g
+                self.change_gutter = !self.change_gutter;
+                self.options_mode = false;
+            }
+            Key::Char('h') => { // Toggle highlighting the word under the cursor on h
+                self.config.highlight_word = !self.config.highlight_word;
+                self.options_mode = false;
+            }
+            Key::Char('v') => { // Toggle read-only on v
+                self.read_only = !self.read_only;
+                self.options_mode = false;
+            }
+            Key::Char('t') => { // Toggle trimming trailing whitespace on save on t
+                self.config.trim_whitespace_on_save = !self.config.trim_whitespace_on_save;
+                self.options_mode = false;
+            }
+            Key::Char('i') => { // Toggle auto-indent on i
+                self.config.auto_indent = !self.config.auto_indent;
+                self.options_mode = false;
+            }
+            Key::Char('m') => { // Toggle line continuation markers on m
+                self.line_markers = !self.line_markers;
+                self.options_mode = false;
+            }
+            Key::Char(' ') => self.options_page += 1, // Next page of the help on Space, wrapping round to the first
+            Key::Esc => self.options_mode = false, // Exit options mode on ESC
+            _ => (), // Ignore all other keys
+        }
+        Ok(())
+    }
+
+    // Enters options mode with the help on its first page
+    fn open_options(&mut self) {
+        self.options_mode = true;
+        self.options_page = 0;
+    }
+
+    // Status bar text for the showing page of the options help, packing as many keys as fit the terminal width on each page
+    fn options_help(&self) -> String {
+        let more = " / Space: More ";
+        let width = self.terminal.width.saturating_sub("[Options 9/9] ".len() + more.len());
+        let mut pages: Vec<String> = vec![];
+        for entry in OPTIONS_HELP {
+            match pages.last_mut() {
+                Some(page) if page.len() + " / ".len() + entry.len() <= width => *page = format!("{} / {}", page, entry),
+                _ => pages.push(entry.to_string()), // A key too long for any page still gets one to itself
+            }
+        }
+        let page = self.options_page % pages.len();
+        match pages.len() {
+            1 => format!("[Options] {} ", pages[0]),
+            count => format!("[Options {}/{}] {}{}", page + 1, count, pages[page], more),
+        }
+    }
+
+    // Asks for a line of text in the status bar, returning None if cancelled with ESC
+    fn prompt(&mut self, label: &str) -> Result<Option<String>, std::io::Error> {
+        let mut input = String::new(); // Text typed so far
+        loop {
+            self.status_bar = format!("{}{}", label, input);
+            self.draw()?;
+            self.show_status_cursor()?;
+            match self.read_key()? {
+                Key::Char('\n') => return Ok(Some(input)), // Enter accepts
+                Key::Char(c) => input.push(c),
+                Key::Backspace => {
+                    input.pop();
+                }
+                Key::Esc => return Ok(None), // ESC cancels
+                _ => (), // Ignore all other keys
+            }
+        }
+    }
+
+    // Asks a yes/no question in the status bar, only y counts as yes
+    fn confirm(&mut self, question: &str) -> Result<bool, std::io::Error> {
+        self.status_bar = question.to_string();
+        self.draw()?;
+        self.show_status_cursor()?;
+        Ok(self.read_key()? == Key::Char('y'))
+    }
+
+    // Shows the cursor at the end of the status bar text without losing the document cursor
+    fn show_status_cursor(&mut self) -> Result<(), std::io::Error> {
+        let position = self.terminal.get_cursor_position(); // Document cursor, restored after moving
+        let x = self.status_bar.chars().count().min(self.terminal.width.saturating_sub(1)).saturating_sub(self.terminal.x_offset); // The status bar isn't indented by the gutter, or drawn past the right edge
+        self.terminal.set_cursor_position(terminal::Position { x, y: self.terminal.height - 1 });
+        self.terminal.cursor_position = position;
+        self.terminal.flush()
+    }
+
+    // Lets the user pick a file under the base directory by fuzzy matching its path, then opens it
+    fn find_file(&mut self) -> Result<(), std::io::Error> {
+        let root = self.base_directory.clone().unwrap_or_else(|| PathBuf::from("."));
+        self.find_file_in(&root)
+    }
+
+    // Lets the user pick a file under root by fuzzy matching its path, then opens it
+    fn find_file_in(&mut self, root: &Path) -> Result<(), std::io::Error> {
+        if self.dirty { // Opening replaces the buffer
+            self.set_status_message("Save or discard changes before opening another file ".to_string());
+            return Ok(());
+        }
+        let files = finder::list_files(root, 20_000); // Bound the walk so huge trees don't stall
+        let mut query = String::new(); // Text typed so far
+        let mut selected = 0; // Index of the highlighted match
+        loop {
+            let mut matches: Vec<(i64, &String)> = files.iter()
+                .filter_map(|file| finder::fuzzy_score(&query, file).map(|score| (score, file)))
+                .collect();
+            matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score)); // Best match first
+            matches.truncate(self.terminal.height.saturating_sub(1).min(10)); // Only the top matches fit in the overlay
+            selected = selected.min(matches.len().saturating_sub(1));
+
+            self.status_bar = format!("Open file: {}", query);
+            self.draw()?;
+            // Draw the matches over the top of the document
+            for (row, (_, file)) in matches.iter().enumerate() {
+                let file: String = file.chars().take(self.terminal.width).collect();
+                self.terminal.goto(0, row);
+                self.terminal.clear_line();
+                self.terminal.set_inverted(row == selected);
+                self.terminal.write(&file);
+                self.terminal.set_inverted(false);
+            }
+            self.redraw_all(); // Rows the matches covered
+            self.show_status_cursor()?;
+
+            match self.read_key()? {
+                Key::Char('\n') => { // Enter opens the highlighted match
+                    if let Some((_, file)) = matches.get(selected) {
+                        let path = root.join(file);
+                        let path = path.strip_prefix(".").unwrap_or(&path).to_string_lossy().to_string(); // No ./ on files picked from the current directory
+                        self.open_file(&path);
+                    }
+                    return Ok(());
+                }
+                Key::Esc => return Ok(()), // ESC cancels
+                Key::Up => selected = selected.saturating_sub(1),
+                Key::Down => selected += 1,
+                Key::Backspace => {
+                    query.pop();
+                    selected = 0;
+                }
+                Key::Char(c) => {
+                    query.push(c);
+                    selected = 0;
+                }
+                _ => (), // Ignore all other keys
+            }
+        }
+    }
+
+    // Replaces the whole buffer with text and moves the cursor to the start
+    // Together with content() and apply_action() this drives the editor without reading keys, for embedding and tests
+    #[allow(dead_code)]
+    pub fn set_content(&mut self, text: &str) {
+        self.record_edit(0..self.open_document.lines.len(), false, |editor| {
+            let path = std::mem::take(&mut editor.open_document.path); // Keep the buffer's path
+            editor.open_document = Document::from_text(text);
+            editor.open_document.path = path;
+            editor.scroll_position = 0;
+            editor.terminal.set_cursor_position(terminal::Position::default());
+        });
+        self.dirty = true;
+        self.document_changed();
+    }
+
+    // Returns the whole buffer as text, as it would be saved
+    #[allow(dead_code)]
+    pub fn content(&self) -> String {
+        self.open_document.to_text()
+    }
+
+    // Returns the cursor position within the document (line index in y rather than screen row)
+    pub fn cursor(&self) -> terminal::Position {
+        let position = self.terminal.get_cursor_position();
+        terminal::Position { x: position.x, y: position.y + self.scroll_position }
+    }
+
+    // Opens the file at path as the document, warning in the status bar if it is unusually large
+    // A directory opens the file finder inside it instead, and devices, sockets and pipes are refused
+    pub fn open_file(&mut self, path: &str) {
+        match Document::file_kind(path) {
+            FileKind::Directory => {
+                if let Err(error) = self.find_file_in(Path::new(path)) {
+                    self.set_status_message(format!("Could not list {}: {} ", path, error));
+                }
+                return;
+            }
+            FileKind::Special(kind) => {
+                self.set_status_message(format!("{} is a {}, not a regular file, so it wasn't opened ", path, kind));
+                return;
+            }
+            FileKind::Regular | FileKind::Missing => (),
+        }
+        let size = Document::file_size(path).unwrap_or(0);
+        let is_large = size > self.config.large_file_threshold;
+        if is_large { // Let the user know why nothing is happening while the file loads
+            self.terminal.write(&format!("Loading {} ({})...\r\n", path, format_size(size)));
+            let _ = self.terminal.flush();
+            self.redraw_all();
+        }
+        let is_lazy = size > self.config.lazy_load_threshold;
+        let document = if is_lazy { Document::from_file_lazy(path) } else { Document::from_file(path) };
+        self.open_document = match document {
+            Ok(document) => document,
+            Err(error) if error.kind() == std::io::ErrorKind::InvalidData => { // Editing it as text would corrupt it on save
+                self.set_status_message(format!("Cannot open binary file {} ", path));
+                return;
+            }
+            Err(error) => {
+                self.set_status_message(format!("Could not open {}: {} ", path, error));
+                return;
+            }
+        };
+        self.history.clear();
+        recent::add(&mut self.recent_files, path);
+        self.scroll_position = 0;
+        self.h_scroll = 0;
+        self.terminal.set_cursor_position(terminal::Position::default());
+        self.dirty = false;
+        self.document_changed();
+        if is_large {
+            self.set_status_message(format!(
+                "Warning: {} is a large file ({}, {} lines), editing may be slow{} ",
+                path,
+                format_size(size),
+                self.open_document.lines.len(),
+                if self.open_document.is_lazy() { " and reads the whole file first" } else { "" }
+            ));
+        }
+        if let Some(lock) = self.open_document.find_lock_file(&self.config.lock_files) {
+            if self.config.read_only_when_locked {
+                self.read_only = true;
+                self.set_status_message(format!(
+                    "Warning: {} suggests another editor has this file open, opened read-only (v in the options menu allows editing) ",
+                    lock.display()
+                ));
+            } else {
+                self.set_status_message(format!(
+                    "Warning: {} suggests another editor has this file open, edits may clobber each other ",
+                    lock.display()
+                ));
+            }
+        }
+    }
+
+    // Asks for a character and a count, then types the character that many times at the cursor
+    // Counts of more than ten screen widths need confirming, in case of a typo
+    fn insert_repeated(&mut self) -> Result<(), std::io::Error> {
+        let c = match self.prompt("Character to repeat: ")? {
+            Some(text) if text.chars().count() == 1 => text.chars().next().unwrap(),
+            Some(text) => {
+                self.set_status_message(format!("Expected a single character, got {} ", text));
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let count: usize = match self.prompt(&format!("Repeat {} how many times: ", c))? {
+            Some(count) => match count.trim().parse() {
+                Ok(count) => count,
+                Err(_) => {
+                    self.set_status_message(format!("Not a count: {} ", count));
+                    return Ok(());
+                }
+            },
+            None => return Ok(()),
+        };
+        if count > self.terminal.width * 10 && !self.confirm(&format!("Really insert {} characters? (y/n) ", count))? {
+            return Ok(());
+        }
+        if count > 0 {
+            self.perform(Action::Insert(c.to_string().repeat(count)));
+        }
+        Ok(())
+    }
+
+    // Moves the cursor to a 1-based column on the current line, clamped to the line's end
+    fn go_to_column(&mut self, column: &str) {
+        let column: usize = match column.trim().parse() {
+            Ok(column) if column >= 1 => column,
+            _ => {
+                self.set_status_message(format!("Not a column number: {} ", column));
+                return;
+            }
+        };
+        let mut position = self.terminal.get_cursor_position();
+        position.x = (column - 1).min(self.open_document.line_len(position.y + self.scroll_position));
+        self.terminal.set_cursor_position(position);
+    }
+
+    // Moves the cursor to a 1-based line, clamped to the last line, keeping the column where the line is long enough
+    fn go_to_line(&mut self, line: &str) {
+        let line: usize = match line.trim().parse() {
+            Ok(line) if line >= 1 => line,
+            _ => {
+                self.set_status_message(format!("Not a line number: {} ", line));
+                return;
+            }
+        };
+        let line = (line - 1).min(self.open_document.lines.len() - 1);
+        self.move_cursor_to(line, self.terminal.get_cursor_position().x);
+    }
+
+    // Moves the cursor to the bracket matching the one it is on, scrolling to it if it is off screen
+    fn jump_to_matching_bracket(&mut self) {
+        let cursor = self.cursor();
+        let on_bracket = self.open_document.line(cursor.y).and_then(|line| line.chars().nth(cursor.x)).filter(|c| "()[]{}".contains(*c));
+        match (on_bracket, self.scan_lines(|lines| matching_bracket(lines, cursor.y, cursor.x))) {
+            (Some(_), Some((line, column))) => self.move_cursor_to(line, column),
+            (Some(bracket), None) => self.set_status_message(format!("No bracket matches {} ", bracket)),
+            (None, _) => self.set_status_message("Not on a bracket ".to_string()),
+        }
+    }
+
+    // Drops blank lines at the end of the document, leaving it ending in a single newline
+    fn trim_trailing_blank_lines(&mut self) {
+        let mut trimmed = false;
+        self.record_edit(0..self.open_document.lines.len(), false, |editor| trimmed = editor.open_document.trim_trailing_blank_lines());
+        if trimmed {
+            self.dirty = true;
+            self.clamp_viewport();
+            self.document_changed();
+        }
+    }
+
+    // Strips trailing spaces and tabs from every line of the document, as one undoable edit
+    fn trim_trailing_whitespace(&mut self) {
+        let mut trimmed = false;
+        self.record_edit(0..self.open_document.lines.len(), false, |editor| trimmed = editor.open_document.trim_trailing_whitespace());
+        if trimmed {
+            self.dirty = true;
+            self.document_changed();
+        }
+    }
+
+    // Puts the buffer back to how it was when loaded or last saved, without touching the file on disk
+    pub fn discard_changes(&mut self) {
+        let original_lines = self.open_document.original_lines.clone();
+        self.record_edit(0..self.open_document.lines.len(), false, |editor| editor.open_document.lines = original_lines);
+        self.history.mark_saved();
+        self.dirty = false;
+        self.clamp_viewport();
+        self.document_changed();
+    }
+
+    // Re-reads the open document from disk, keeping the cursor where it was if that line still exists
+    pub fn reload(&mut self) {
+        if self.dirty { // Reloading would throw away the changes
+            self.set_status_message("Save or discard changes before reloading ".to_string());
+            return;
+        }
+        if self.open_document.path.is_empty() { // Nothing on disk to reload from
+            return;
+        }
+        let path = &self.open_document.path;
+        let document = if self.open_document.is_lazy() { Document::from_file_lazy(path) } else { Document::from_file(path) };
+        self.open_document = match document {
+            Ok(document) => document,
+            Err(error) => {
+                self.set_status_message(format!("Could not reload {}: {} ", self.open_document.path, error));
+                return;
+            }
+        };
+        self.history.clear();
+        self.clamp_viewport();
+        self.document_changed();
+    }
+
+    // Pulls the cursor and scroll position back inside the document and the screen
+    // Needed whenever the document can shrink underneath the cursor
+    pub fn clamp_viewport(&mut self) {
+        let mut position = self.terminal.get_cursor_position();
+        let rows = self.terminal.height.saturating_sub(1).max(1); // Rows available for text
+        let line = (position.y + self.scroll_position).min(self.open_document.lines.len().saturating_sub(1)); // Cursor line, kept inside the document
+        if self.open_document.lines.len() <= rows { // The whole document fits, so show all of it
+            self.scroll_position = 0;
+        } else if self.scroll_position > line { // Cursor line is above the screen
+            self.scroll_position = line;
+        } else if line >= self.scroll_position + rows { // Cursor line is below the screen
+            self.scroll_position = line + 1 - rows;
+        }
+        position.y = line - self.scroll_position;
+        position.x = position.x.min(self.open_document.line_len(line)); // Cursor column, kept inside the line
+        self.terminal.set_cursor_position(position);
+    }
+
+    // Asks for a line range and a path, then writes just those lines there
+    fn export_lines(&mut self) -> Result<(), std::io::Error> {
+        let range = match self.prompt("Write lines (first,last): ")? {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let total = self.open_document.lines.len();
+        let bounds: Vec<Option<usize>> = range.split(',').map(|number| number.trim().parse().ok()).collect();
+        let (first, last) = match bounds[..] {
+            [Some(first), Some(last)] if first >= 1 && first <= last => (first, last.min(total)),
+            [Some(line)] if line >= 1 => (line, line), // A single line
+            _ => {
+                self.set_status_message(format!("Not a line range: {} ", range));
+                return Ok(());
+            }
+        };
+        if first > total {
+            self.set_status_message(format!("The document only has {} lines ", total));
+            return Ok(());
+        }
+        if let Some(path) = self.prompt("Write to: ")? {
+            let path = self.resolve_path(&path);
+            let written = self.open_document.load_lines(first - 1..last) // Lines of a lazily loaded document that haven't been read
+                .and_then(|_| self.open_document.write_lines(first - 1, last, &path));
+            self.set_status_message(match written {
+                Ok(()) => format!("Wrote lines {}-{} to {} ", first, last, path),
+                Err(error) => format!("Could not write {}: {} ", path, error),
+            });
+        }
+        Ok(())
+    }
+
+    // Replaces the whole document with the output of command run on it, leaving it alone if the command fails
+    fn filter_document(&mut self, command: &str) {
+        if !self.materialize() {
+            return;
+        }
+        let text = self.open_document.lines.join("\n") + "\n"; // Filters like sort expect a final newline
+        match self.run_command(command, Some(&text)) {
+            Ok(output) => {
+                self.record_edit(0..self.open_document.lines.len(), false, |editor| {
+                    editor.open_document.lines = Document::from_text(&output).lines;
+                });
+                self.dirty = true;
+                self.clamp_viewport();
+                self.document_changed();
+            }
+            Err(message) => self.set_status_message(message),
+        }
+    }
+
+    // Runs command with the configured shell in the base directory, feeding it input if given
+    // Returns its stdout without the final newline, or a status message describing the failure
+    fn run_command(&self, command: &str, input: Option<&str>) -> Result<String, String> {
+        let mut child = std::process::Command::new(&self.config.shell)
+            .arg("-c")
+            .arg(command)
+            .current_dir(self.base_directory.clone().unwrap_or_else(|| PathBuf::from(".")))
+            .stdin(if input.is_some() { std::process::Stdio::piped() } else { std::process::Stdio::null() })
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("Could not run {}: {} ", command, error))?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // Write from a thread so a command that fills its stdout pipe before reading all its input can't deadlock
+            let input = input.to_string();
+            std::thread::spawn(move || io::Write::write_all(&mut stdin, input.as_bytes()));
+        }
+        let output = child.wait_with_output().map_err(|error| format!("Could not run {}: {} ", command, error))?;
+        if !output.status.success() { // Report the first line of stderr along with the exit status
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match stderr.lines().next() {
+                Some(error) => format!("{} failed ({}): {} ", command, output.status, error),
+                None => format!("{} failed ({}) ", command, output.status),
+            });
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n");
+        Ok(stdout.strip_suffix('\n').unwrap_or(&stdout).to_string())
+    }
+
+    // Resolves a user-given path against the base directory -- absolute paths are left as they are
+    pub fn resolve_path(&self, path: &str) -> String {
+        match &self.base_directory {
+            Some(base) if Path::new(path).is_relative() => base.join(path).to_string_lossy().to_string(),
+            _ => path.to_string(),
+        }
+    }
+
+    // Reads a termion key from stdin, ignoring mouse events, for prompts waiting on an answer
+    fn read_key(&mut self) -> Result<Key, std::io::Error> {
+        loop {
+            if let Event::Key(key) = self.read_event()? {
+                return Ok(key);
+            }
+        }
+    }
+
+    // Reads a key or mouse event from stdin, handling terminal focus events in between
+    fn read_event(&mut self) -> Result<Event, std::io::Error> {
+        loop {
+            let event = match self.input.next() {
+                Some(Ok(Event::Key(key))) => Event::Key(key),
+                Some(Ok(Event::Mouse(mouse))) => Event::Mouse(mouse),
+                Some(Ok(Event::Unsupported(sequence))) if sequence == b"\x1b[O" => { // Focus out
+                    self.focus_lost();
+                    continue;
+                }
+                Some(Ok(Event::Unsupported(sequence))) => match unparsed_key(&sequence) { // Ctrl+arrows and the like
+                    Some(key) => Event::Key(key),
+                    None => continue,
+                },
+                Some(Err(error)) => return Err(error),
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Input closed")),
+            };
+            self.last_key_time = std::time::Instant::now();
+            return Ok(event);
+        }
+    }
+
+    // Auto-saves a modified document when the terminal loses focus, if enabled
+    fn focus_lost(&mut self) {
+        if self.config.auto_save_on_focus_lost && self.dirty && !self.open_document.path.is_empty() {
+            self.save();
+        }
+    }
+
+    // Saves the open document, creating missing parent directories if the config allows it
+    // Returns whether the document was saved, a failure is reported in the status bar
+    fn save(&mut self) -> bool {
+        if self.read_only {
+            self.set_status_message("Read-only, not saved (v in the options menu allows editing) ".to_string());
+            return false;
+        }
+        if self.open_document.path.is_empty() { // Untitled buffer
+            self.set_status_message("No file name to save to ".to_string());
+            return false;
+        }
+        if !self.materialize() { // Saving writes every line
+            return false;
+        }
+        if self.config.create_parent_dirs {
+            if let Err(error) = self.open_document.create_parent_dirs() {
+                self.set_status_message(format!("Could not create directory for {}: {} ", self.open_document.path, error));
+                return false;
+            }
+        }
+        if self.config.trim_blank_lines_on_save {
+            self.trim_trailing_blank_lines();
+        }
+        if self.config.trim_whitespace_on_save && self.config.trim_whitespace_in_buffer {
+            self.trim_trailing_whitespace();
+        }
+        if self.config.ensure_trailing_newline && self.open_document.lines != [""] {
+            self.open_document.trailing_newline = true;
+        }
+        if let Err(error) = self.open_document.rotate_backups(self.config.backup_count) {
+            self.set_status_message(format!("Could not back up {}: {} ", self.open_document.path, error));
+            return false;
+        }
+        if let Err(error) = self.open_document.save(self.config.trim_whitespace_on_save) {
+            self.set_status_message(format!("Could not save {}: {} ", self.open_document.path, error));
+            return false;
+        }
+        self.dirty = false;
+        self.history.mark_saved();
+        self.open_document.original_lines = self.open_document.lines.clone(); // What discarding changes goes back to
+        self.set_status_message(format!("Saved {} lines to {} ", self.open_document.lines.len(), self.open_document.path));
+        for observer in &mut self.observers {
+            observer.saved(&self.open_document.path);
+        }
+        true
+    }
+
+    // Saves like save, first asking for a file name if the buffer is untitled
+    // Returns whether the document was saved, false if the name prompt was cancelled
+    fn save_asking_for_name(&mut self) -> Result<bool, std::io::Error> {
+        if self.open_document.path.is_empty() && !self.read_only { // Read-only refuses to save without asking
+            match self.prompt("Save as: ")? {
+                Some(name) if !name.trim().is_empty() => {
+                    self.open_document.path = self.resolve_path(name.trim());
+                    recent::add(&mut self.recent_files, &self.open_document.path);
+                }
+                _ => return Ok(false), // Cancelled
+            }
+        }
+        Ok(self.save())
+    }
+
+    // Takes a termion key and moves cursor accordingly
+    pub fn arrow_move(&mut self, key: Key) {
+        let mut position = self.terminal.get_cursor_position();
+        match key {
+            Key::Up => { // Up arrow
+                let column = *self.preferred_column.get_or_insert(self.cursor_display_column()); // Screen column to return to on longer lines
+                if position.y > 0 { // If cursor is not at top of screen
+                    position.y = position.y.saturating_sub(1); // Move cursor up 1
+                } else if position.y == 0 && self.scroll_position > 0 { // If cursor is at top of screen and not at top of document
+                    self.scroll_position = self.scroll_position.saturating_sub(1); // Scroll up 1
+                }
+                position.x = self.column_at_display(position.y + self.scroll_position, column); // Preferred column, clamped to the line
+            }
+            Key::Down => { // Down arrow
+                let column = *self.preferred_column.get_or_insert(self.cursor_display_column()); // Screen column to return to on longer lines
+                let mut is_at_end_of_document = (position.y + self.scroll_position + 1) == self.open_document.lines.len(); // If cursor is at end of document
+                if is_at_end_of_document && self.config.extend_past_end && !self.read_only { // Grow the document into the space below instead of stopping
+                    let end = self.open_document.lines.len();
+                    self.record_edit(end..end, false, |editor| editor.open_document.lines.push(String::new()));
+                    self.dirty = true;
+                    self.document_changed();
+                    is_at_end_of_document = false;
+                }
+                if !is_at_end_of_document && position.y < self.terminal.height.saturating_sub(2) { // If cursor is not at bottom of screen and not at end of document
+                    position.y = position.y.saturating_add(1); // Move cursor down 1
+                } else if !is_at_end_of_document && position.y == self.terminal.height.saturating_sub(2) { // If cursor is at bottom of screen and not at end of document
+                    self.scroll_position = self.scroll_position.saturating_add(1); // Scroll down 1
+                }
+                position.x = self.column_at_display(position.y + self.scroll_position, column); // Preferred column, clamped to the line
+            }
+            Key::PageUp | Key::PageDown => { // Scroll a screen up or down, moving the cursor line along with it
+                let column = *self.preferred_column.get_or_insert(self.cursor_display_column()); // Screen column to return to on longer lines
+                let rows = self.terminal.height.saturating_sub(1).max(1); // Rows above the status bar
+                let last_line = self.open_document.lines.len() - 1;
+                let line = position.y + self.scroll_position; // Current line index
+                let line = if key == Key::PageDown {
+                    self.scroll_position = (self.scroll_position + rows).min((last_line + 1).saturating_sub(rows)); // Never scroll the last line above the bottom
+                    (line + rows).min(last_line)
+                } else {
+                    self.scroll_position = self.scroll_position.saturating_sub(rows);
+                    line.saturating_sub(rows)
+                };
+                position.y = line - self.scroll_position;
+                position.x = self.column_at_display(line, column); // Preferred column, clamped to the line
+            }
+            Key::Left if position.x > 0 => { // Left arrow when cursor is not at beginning of line
+                position.x = position.x.saturating_sub(1); // Move cursor left 1
+            }
+            Key::Right if position.x < self.open_document.line_len(position.y + self.scroll_position) => { // Right arrow when cursor is not at end of line
+                position.x = position.x.saturating_add(1); // Move cursor right 1
+            }
+            Key::Alt('b') | Key::Alt('f') => { // Previous or next word start, crossing line breaks
+                let (line, column) = (position.y + self.scroll_position, position.x);
+                let scan = if key == Key::Alt('b') { previous_word_start } else { next_word_start };
+                if let Some((line, column)) = self.scan_lines(|lines| Some(scan(lines, line, column))) {
+                    self.move_cursor_to(line, column);
+                }
+                position = self.terminal.get_cursor_position();
+            }
+            Key::Home => position.x = 0, // Home key moves cursor to beginning of line
+            Key::End => position.x = self.open_document.line_len(position.y + self.scroll_position), // End key moves cursor to end of line
+            _ => (), // Ignore all other keys
+        }
+        self.terminal.set_cursor_position(position); // Update cursor position
+        self.scroll_to_cursor_column();
+    }
+
+    // Scrolls horizontally just far enough to bring the cursor's column on screen
+    fn scroll_to_cursor_column(&mut self) {
+        let x = self.cursor_display_column();
+        let cursor = self.cursor();
+        let cells = self.open_document.line(cursor.y).and_then(|line| line.chars().nth(cursor.x)).map_or(1, |c| advance(x, c, self.tab_width())); // All of a wide character
+        let width = self.text_width().max(1);
+        if x < self.h_scroll { // Left of the view
+            self.h_scroll = x;
+        } else if x + cells > self.h_scroll + width { // Right of the view
+            self.h_scroll = (x + cells).saturating_sub(width).min(x);
+        }
+    }
+}
+
+// Formats a byte count for display, e.g. 1536 -> "1.5 KB"
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 { // Scale down until the number is readable
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// The screen cells line is drawn in, each with the character shown there and the index of the character it comes from
+// Tabs become spaces up to the next multiple of tab_width, and the cells after the first of a wide character hold None
+fn display_cells(line: &str, tab_width: usize) -> Vec<(Option<char>, usize)> {
+    let mut cells = vec![];
+    for (index, c) in line.chars().enumerate() {
+        if c == '\t' {
+            let width = tab_width - cells.len() % tab_width;
+            cells.extend(std::iter::repeat_n((Some(' '), index), width));
+        } else {
+            cells.push((Some(c), index));
+            cells.extend(std::iter::repeat_n((None, index), char_width(c) - 1));
+        }
+    }
+    cells
+}
+
+// Screen cells c takes up -- two for wide characters (CJK, emoji) and as many as the caret notation of a control character
+// Tabs depend on the column they start at, so are worked out by the callers
+fn char_width(c: char) -> usize {
+    if c.is_control() {
+        return caret_notation(c).len();
+    }
+    match c as u32 {
+        0x1100..=0x115F // Hangul Jamo
+        | 0x2E80..=0x303E // CJK radicals and punctuation
+        | 0x3041..=0x33FF // Kana, Bopomofo and CJK compatibility
+        | 0x3400..=0x4DBF | 0x4E00..=0x9FFF // CJK ideographs
+        | 0xA000..=0xA4CF // Yi
+        | 0xAC00..=0xD7A3 // Hangul syllables
+        | 0xF900..=0xFAFF // CJK compatibility ideographs
+        | 0xFE30..=0xFE4F // CJK compatibility forms
+        | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6 // Fullwidth forms
+        | 0x1F300..=0x1F64F | 0x1F680..=0x1F6FF | 0x1F900..=0x1F9FF // Emoji
+        | 0x20000..=0x3FFFD => 2, // More CJK ideographs
+        _ => 1,
+    }
+}
+
+// Screen cells character c takes up when it starts at screen column width
+fn advance(width: usize, c: char, tab_width: usize) -> usize {
+    if c == '\t' { tab_width - width % tab_width } else { char_width(c) }
+}
+
+// Screen column character column of line is drawn at, the width of the whole line for columns past its end
+fn display_column(line: &str, column: usize, tab_width: usize) -> usize {
+    line.chars().take(column).fold(0, |width, c| width + advance(width, c, tab_width))
+}
+
+// Character column of line drawn at or just before screen column display (on a tab or wide character for columns inside one),
+// the end of the line if it's shorter
+fn column_at_display(line: &str, display: usize, tab_width: usize) -> usize {
+    let mut width = 0; // Screen column the next character starts at
+    for (index, c) in line.chars().enumerate() {
+        width += advance(width, c, tab_width);
+        if width > display {
+            return index;
+        }
+    }
+    line.chars().count()
+}
+
+// Does key change the document in editor mode? Read-only mode ignores these
+fn is_editing_key(key: Key) -> bool {
+    matches!(key, Key::Char(_) | Key::Backspace | Key::Delete | Key::Ctrl('t' | 'v' | 'x' | 'u' | 'e' | 'z' | 'y' | 'r') | Key::Alt('x' | 'u'))
+}
+
+// c in the other case, which can be more than one character, or c itself if it has no case
+fn toggled_case(c: char) -> String {
+    if c.is_lowercase() {
+        c.to_uppercase().collect()
+    } else if c.is_uppercase() {
+        c.to_lowercase().collect()
+    } else {
+        c.to_string()
+    }
+}
+
+// Shifts the lines of text from the indentation of its first line to indent
+// The first line loses its indentation, as it goes after the cursor, and the others swap the first line's indentation for indent
+// Lines indented less than the first line end up at indent, and blank lines stay blank
+fn reindent(text: &str, indent: &str) -> String {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let first_indent: String = lines[0].chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+    let mut shifted = vec![lines[0][first_indent.len()..].to_string()];
+    for line in &lines[1..] {
+        let shifted_line = match line.strip_prefix(first_indent.as_str()) {
+            _ if line.trim().is_empty() => line.to_string(),
+            Some(rest) => format!("{}{}", indent, rest),
+            None => format!("{}{}", indent, line.trim_start_matches([' ', '\t'])),
+        };
+        shifted.push(shifted_line);
+    }
+    shifted.join("\n")
+}
+
+// Position of the bracket matching the one at column of line, as (line, column)
+// Scans forward from an opening bracket and back from a closing one, skipping over nested pairs of the same kind
+// None if there isn't a bracket there or nothing matches it
+fn matching_bracket(lines: &[String], line: usize, column: usize) -> Option<(usize, usize)> {
+    let (deeper, shallower, forward) = match lines.get(line)?.chars().nth(column)? {
+        '(' => ('(', ')', true),
+        '[' => ('[', ']', true),
+        '{' => ('{', '}', true),
+        ')' => (')', '(', false),
+        ']' => (']', '[', false),
+        '}' => ('}', '{', false),
+        _ => return None,
+    };
+    let mut depth = 0;
+    let mut index = line;
+    loop {
+        let chars: Vec<char> = lines[index].chars().collect();
+        let columns: Vec<usize> = match (forward, index == line) {
+            (true, true) => (column..chars.len()).collect(),
+            (true, false) => (0..chars.len()).collect(),
+            (false, true) => (0..=column).rev().collect(),
+            (false, false) => (0..chars.len()).rev().collect(),
+        };
+        for at in columns {
+            if chars[at] == deeper {
+                depth += 1;
+            } else if chars[at] == shallower {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((index, at));
+                }
+            }
+        }
+        index = if forward { index + 1 } else { index.checked_sub(1)? };
+        if index == lines.len() {
+            return None;
+        }
+    }
+}
+
+// Is c part of a word (an identifier) for word highlighting?
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Key to handle an escape sequence termion doesn't parse as
+// Ctrl+Left and Ctrl+Right (or Alt+Left and Alt+Right) are Alt+b and Alt+f, the readline keys for the same word motions,
+// and Ctrl+PageUp and Ctrl+PageDown are Alt+p and Alt+n, which switch file
+fn unparsed_key(sequence: &[u8]) -> Option<Key> {
+    match sequence {
+        b"\x1b[1;5D" | b"\x1b[1;3D" => Some(Key::Alt('b')),
+        b"\x1b[1;5C" | b"\x1b[1;3C" => Some(Key::Alt('f')),
+        b"\x1b[5;5~" => Some(Key::Alt('p')),
+        b"\x1b[6;5~" => Some(Key::Alt('n')),
+        _ => None,
+    }
+}
+
+// Start of the next word after column of line (the end of the document if there isn't one)
+fn next_word_start(lines: &[String], mut line: usize, column: usize) -> (usize, usize) {
+    let mut text: Vec<char> = lines[line].chars().collect();
+    let mut column = column.min(text.len());
+    while column < text.len() && is_word_char(text[column]) { // Off the end of the word the cursor is in
+        column += 1;
+    }
+    loop { // Then past everything that isn't a word, onto the following lines
+        while column < text.len() && !is_word_char(text[column]) {
+            column += 1;
+        }
+        if column < text.len() || line + 1 == lines.len() {
+            return (line, column);
+        }
+        line += 1;
+        text = lines[line].chars().collect();
+        column = 0;
+    }
+}
+
+// Start of the word before column of line (the start of the document if there isn't one)
+fn previous_word_start(lines: &[String], mut line: usize, column: usize) -> (usize, usize) {
+    let mut text: Vec<char> = lines[line].chars().collect();
+    let mut column = column.min(text.len());
+    loop { // Back over everything that isn't a word, onto the earlier lines
+        while column > 0 && !is_word_char(text[column - 1]) {
+            column -= 1;
+        }
+        if column > 0 || line == 0 {
+            break;
+        }
+        line -= 1;
+        text = lines[line].chars().collect();
+        column = text.len();
+    }
+    while column > 0 && is_word_char(text[column - 1]) { // Then to the start of the word
+        column -= 1;
+    }
+    (line, column)
+}
+
+// Character columns where word appears in line as a whole word
+fn word_occurrences<'a>(line: &'a str, word: &'a str) -> impl Iterator<Item = usize> + 'a {
+    line.match_indices(word)
+        .filter(move |(index, _)| {
+            let before = line[..*index].chars().next_back();
+            let after = line[index + word.len()..].chars().next();
+            !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+        })
+        .map(move |(index, _)| line[..index].chars().count())
+}
+
+// The character a key sends, for inserting it literally -- None for keys with no single character (arrows, F keys, ...)
+fn literal_char(key: Key) -> Option<char> {
+    match key {
+        Key::Char(c) => Some(c),
+        Key::Ctrl(c) if c.is_ascii() => Some((c as u8 & 0x1f) as char),
+        Key::Esc => Some('\x1b'),
+        Key::Backspace => Some('\x7f'),
+        Key::Null => Some('\0'),
+        _ => None,
+    }
+}
+
+// Printable stand-in for a control character -- ^@ to ^_ and ^? for ASCII ones, <hex> for the rest
+fn caret_notation(c: char) -> String {
+    match c as u32 {
+        0..=0x1f => format!("^{}", (c as u8 + b'@') as char),
+        0x7f => "^?".to_string(),
+        code => format!("<{:02x}>", code),
+    }
+}
+
+// Current local time as HH:MM:SS
+fn clock() -> String {
+    let mut now: libc::time_t = 0;
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::time(&mut now);
+        libc::localtime_r(&now, &mut local);
+    }
+    format!("{:02}:{:02}:{:02}", local.tm_hour, local.tm_min, local.tm_sec)
+}
+
+// Milliseconds until the wall clock ticks over to the next second
+fn millis_to_next_second() -> u64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    1000 - now.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::{Backend, Terminal};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Backend with a fixed size that records the text written to it
+    struct TestBackend {
+        width: usize,
+        height: usize,
+        output: Rc<RefCell<String>>, // Everything written, shared with the test
+    }
+
+    impl Backend for TestBackend {
+        fn size(&self) -> Option<(usize, usize)> {
+            Some((self.width, self.height))
+        }
+        fn clear(&mut self) {}
+        fn clear_line(&mut self) {}
+        fn goto(&mut self, _x: usize, _y: usize) {}
+        fn write(&mut self, text: &str) {
+            self.output.borrow_mut().push_str(text);
+        }
+        fn set_foreground(&mut self, _color: Color) {}
+        fn set_background(&mut self, _color: Color) {}
+        fn set_inverted(&mut self, _inverted: bool) {}
+        fn set_cursor_visibility(&mut self, _visible: bool) {}
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    // Observer that logs every event it hears about
+    struct RecordingObserver {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Observer for RecordingObserver {
+        fn document_changed(&mut self, document: &Document) {
+            self.events.borrow_mut().push(format!("changed {}", document.lines.join("|")));
+        }
+        fn cursor_moved(&mut self, line: usize, column: usize) {
+            self.events.borrow_mut().push(format!("cursor {},{}", line, column));
+        }
+        fn mode_changed(&mut self, mode: &str) {
+            self.events.borrow_mut().push(format!("mode {}", mode));
+        }
+    }
+
+    // Editor on an 80x10 terminal (9 text rows) holding text, with the cursor at the start
+    fn editor(text: &str) -> Editor {
+        editor_with_output(text).0
+    }
+
+    // Same as editor, also returning the text written to the terminal
+    fn editor_with_output(text: &str) -> (Editor, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let terminal = Terminal::new(Box::new(TestBackend { width: 80, height: 10, output: output.clone() }));
+        let mut editor = Editor::new(terminal, Config::default());
+        editor.set_content(text);
+        editor.dirty = false;
+        (editor, output)
+    }
+
+    // Feeds keys through the same handlers the main loop uses
+    fn press(editor: &mut Editor, keys: &[Key]) {
+        for key in keys {
+            editor.handle_key(*key).unwrap();
+        }
+    }
+
+    // Keys that type text
+    fn typed(text: &str) -> Vec<Key> {
+        text.chars().map(Key::Char).collect()
+    }
+
+    // The status bar message showing, if any
+    fn status_message(editor: &Editor) -> Option<&str> {
+        editor.status_message.as_ref().map(|(message, _)| message.as_str())
+    }
+
+    // Cursor position in the document as (column, line)
+    fn cursor(editor: &Editor) -> (usize, usize) {
+        let position = editor.cursor();
+        (position.x, position.y)
+    }
+
+    #[test]
+    fn typing_inserts_at_cursor() {
+        let mut editor = editor("world");
+        press(&mut editor, &typed("hello "));
+        assert_eq!(editor.open_document.lines, vec!["hello world"]);
+        assert_eq!(cursor(&editor), (6, 0));
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn enter_splits_line() {
+        let mut editor = editor("helloworld");
+        press(&mut editor, &[Key::Right; 5]);
+        press(&mut editor, &[Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines, vec!["hello", "world"]);
+        assert_eq!(cursor(&editor), (0, 1));
+    }
+
+    #[test]
+    fn backspace_at_line_start_joins_lines() {
+        let mut editor = editor("hello\nworld");
+        press(&mut editor, &[Key::Down, Key::Backspace]);
+        assert_eq!(editor.open_document.lines, vec!["helloworld"]);
+        assert_eq!(cursor(&editor), (5, 0));
+    }
+
+    #[test]
+    fn vertical_movement_clamps_to_line_length() {
+        let mut editor = editor("a long line\nshort");
+        press(&mut editor, &[Key::End, Key::Down]);
+        assert_eq!(cursor(&editor), (5, 1));
+    }
+
+    #[test]
+    fn moving_past_bottom_row_scrolls() {
+        let text = (1..=20).map(|n| n.to_string()).collect::<Vec<_>>().join("\n");
+        let mut editor = editor(&text);
+        press(&mut editor, &[Key::Down; 12]);
+        assert_eq!(cursor(&editor), (0, 12));
+        assert_eq!(editor.scroll_position, 4);
+    }
+
+    #[test]
+    fn repeat_replays_last_insert_run() {
+        let mut editor = editor("");
+        press(&mut editor, &typed("ab"));
+        press(&mut editor, &[Key::Left, Key::Ctrl('r')]);
+        assert_eq!(editor.open_document.lines, vec!["aabb"]);
+    }
+
+    #[test]
+    fn toggle_case_advances_over_non_letters() {
+        let mut editor = editor("a1B");
+        press(&mut editor, &[Key::Ctrl('t'); 3]);
+        assert_eq!(editor.open_document.lines, vec!["A1b"]);
+        assert_eq!(cursor(&editor), (3, 0));
+    }
+
+    #[test]
+    fn toggle_case_covers_a_selection() {
+        let mut editor = editor("one Two\nthree");
+        press(&mut editor, &[Key::Right, Key::Ctrl('b'), Key::Down, Key::Right, Key::Ctrl('t')]);
+        assert_eq!(editor.open_document.lines, vec!["oNE tWO", "THree"]);
+        assert_eq!(cursor(&editor), (1, 0));
+        assert!(editor.selection_anchor.is_none());
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["one Two", "three"]);
+    }
+
+    #[test]
+    fn status_bar_is_cut_to_the_terminal_width() {
+        let (mut editor, output) = editor_with_output("x");
+        editor.terminal.width = 12;
+        editor.status_bar = "Ln 1, Col 1 / 1 lines ESC for Options ".to_string();
+        editor.draw().unwrap();
+        assert!(output.borrow().ends_with("Ln 1, Col 1 \r"));
+        editor.config.status_clock = true;
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        let output = output.borrow();
+        let status = &output[output.rfind("Ln").unwrap()..];
+        assert_eq!(status.len(), 13); // Cut further to fit the clock
+        assert!(status.starts_with("Ln 1") && status[4..].chars().filter(|c| *c == ':').count() == 2);
+    }
+
+    #[test]
+    fn quit_with_unsaved_changes_needs_confirmation() {
+        let mut editor = editor("");
+        press(&mut editor, &typed("x"));
+        assert_eq!(editor.format_status(&editor.config.status_format), "Ln 1, Col 2 / 1 lines[modified] ESC for Options ");
+        press(&mut editor, &[Key::Esc, Key::Char('q')]);
+        assert!(editor.running);
+        press(&mut editor, &[Key::Char('q')]);
+        assert!(!editor.running);
+    }
+
+    #[test]
+    fn reload_of_shrunk_file_clamps_cursor() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_reload", std::process::id()));
+        let long: Vec<String> = (1..=100).map(|n| n.to_string()).collect();
+        std::fs::write(&path, long.join("\n")).unwrap();
+        let mut editor = editor("");
+        editor.open_file(&path.to_string_lossy());
+        press(&mut editor, &[Key::Down; 80]);
+        assert_eq!(cursor(&editor), (0, 80));
+
+        std::fs::write(&path, "a\nb\nc").unwrap();
+        editor.reload();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cursor(&editor), (0, 2));
+        assert!(editor.scroll_position <= 2);
+        assert_eq!(editor.format_status("{line}/{total}"), "3/3");
+        editor.draw().unwrap();
+    }
+
+    #[test]
+    fn vertical_movement_keeps_preferred_column() {
+        let mut editor = editor("a long line\nab\nanother long line");
+        press(&mut editor, &[Key::End, Key::Down]);
+        assert_eq!(cursor(&editor), (2, 1));
+        press(&mut editor, &[Key::Down]);
+        assert_eq!(cursor(&editor), (11, 2));
+        press(&mut editor, &[Key::Left, Key::Up, Key::Up]);
+        assert_eq!(cursor(&editor), (10, 0));
+    }
+
+    #[test]
+    fn discard_changes_restores_loaded_lines() {
+        let mut editor = editor("one\ntwo");
+        press(&mut editor, &[Key::Down, Key::End, Key::Char('\n'), Key::Char('x')]);
+        assert_eq!(editor.open_document.lines, vec!["one", "two", "x"]);
+        editor.discard_changes();
+        assert_eq!(editor.open_document.lines, vec!["one", "two"]);
+        assert_eq!(cursor(&editor), (1, 1));
+        assert!(!editor.dirty);
+    }
+
+    #[test]
+    fn discard_changes_goes_back_to_the_last_save() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_discard_after_save", std::process::id()));
+        let mut editor = editor("a");
+        editor.open_document.path = path.to_string_lossy().to_string();
+        press(&mut editor, &[Key::End, Key::Char('b')]);
+        assert!(editor.save());
+        press(&mut editor, &[Key::Char('c')]);
+        editor.discard_changes();
+        assert_eq!(editor.open_document.lines, vec!["ab"]); // The same as the file on disk
+        assert!(!editor.dirty);
+        assert_eq!(editor.open_document.line_changes(), vec![LineChange::Unchanged]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn enter_at_bottom_keeps_new_line_visible() {
+        let mut editor = editor("");
+        press(&mut editor, &[Key::Char('\n'); 20]);
+        assert_eq!(editor.open_document.lines.len(), 21);
+        assert_eq!(cursor(&editor), (0, 20));
+        assert_eq!(editor.terminal.get_cursor_position().y, 8);
+
+        let mut editor = self::editor("");
+        editor.config.scroll_jump = 4;
+        press(&mut editor, &[Key::Char('\n'); 20]);
+        assert_eq!(cursor(&editor), (0, 20));
+        assert!(editor.terminal.get_cursor_position().y <= 8);
+    }
+
+    #[test]
+    fn start_page_opens_picked_recent_file() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_recent", std::process::id()));
+        std::fs::write(&path, "recent\n").unwrap();
+        let mut editor = editor("");
+        editor.recent_files = vec!["/nonexistent/first".to_string(), path.to_string_lossy().to_string()];
+        press(&mut editor, &[Key::Down, Key::Down, Key::Down]);
+        assert_eq!(editor.start_page_selection, Some(1));
+        editor.draw().unwrap();
+        press(&mut editor, &[Key::Char('\n')]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(editor.open_document.lines, vec!["recent"]);
+        assert_eq!(editor.recent_files[0], path.to_string_lossy());
+
+        let mut editor = self::editor("");
+        editor.recent_files = vec![path.to_string_lossy().to_string()];
+        press(&mut editor, &[Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines, vec!["", ""]);
+    }
+
+    #[test]
+    fn backspace_at_document_start_is_a_no_op() {
+        let mut editor = editor("ab\ncd");
+        press(&mut editor, &[Key::Backspace; 10]);
+        assert_eq!(editor.open_document.lines, vec!["ab", "cd"]);
+        assert_eq!(cursor(&editor), (0, 0));
+
+        press(&mut editor, &[Key::Down, Key::Right]);
+        press(&mut editor, &[Key::Backspace; 10]);
+        assert_eq!(editor.open_document.lines, vec!["d"]);
+        assert_eq!(cursor(&editor), (0, 0));
+        assert_eq!(editor.scroll_position, 0);
+    }
+
+    #[test]
+    fn status_messages_last_a_few_seconds() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_saved_message", std::process::id()));
+        let mut editor = editor("one\ntwo");
+        editor.open_document.path = path.to_string_lossy().to_string();
+        assert!(editor.save());
+        press(&mut editor, &typed("x")); // Keys don't clear it
+        assert_eq!(status_message(&editor), Some(format!("Saved 2 lines to {} ", path.display()).as_str()));
+        editor.expire_status_message();
+        assert!(status_message(&editor).is_some());
+        let set = std::time::Instant::now().checked_sub(std::time::Duration::from_secs(STATUS_MESSAGE_SECONDS)).unwrap();
+        editor.status_message.as_mut().unwrap().1 = set;
+        editor.expire_status_message();
+        assert_eq!(status_message(&editor), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saving_untitled_buffer_is_refused() {
+        let mut editor = editor("");
+        press(&mut editor, &typed("x"));
+        assert!(!editor.save()); // Without asking for a name, as idle quit and auto-save do
+        assert!(editor.dirty);
+        assert_eq!(status_message(&editor), Some("No file name to save to "));
+    }
+
+    #[test]
+    fn control_characters_draw_as_caret_notation() {
+        let (mut editor, output) = editor_with_output("a\x01b\x1b[2J\x7f");
+        editor.draw().unwrap();
+        let output = output.borrow();
+        assert!(output.contains("a^Ab^[[2J^?"));
+        assert!(!output.contains('\x01') && !output.contains('\x7f'));
+        assert_eq!(editor.open_document.lines, vec!["a\x01b\x1b[2J\x7f"]);
+    }
+
+    #[test]
+    fn empty_line_marker_can_be_changed_or_hidden() {
+        let (mut editor, output) = editor_with_output("text");
+        editor.draw().unwrap();
+        assert_eq!(output.borrow().matches('~').count(), 8);
+
+        let (mut editor, output) = editor_with_output("text");
+        editor.config.empty_line_marker = "".to_string();
+        editor.draw().unwrap();
+        assert!(!output.borrow().contains('~'));
+    }
+
+    #[test]
+    fn ctrl_v_inserts_next_key_literally() {
+        let mut editor = editor("");
+        press(&mut editor, &[Key::Ctrl('v'), Key::Esc, Key::Ctrl('v'), Key::Ctrl('a'), Key::Ctrl('v'), Key::Backspace]);
+        assert!(!editor.options_mode);
+        assert_eq!(editor.open_document.lines, vec!["\x1b\x01\x7f"]);
+        assert_eq!(cursor(&editor), (3, 0));
+    }
+
+    #[test]
+    fn vim_mode_normal_and_insert_keys() {
+        let mut editor = editor("one\ntwo");
+        editor.config.vim_mode = true;
+        editor.normal_mode = true;
+        press(&mut editor, &typed("jlx"));
+        assert_eq!(editor.open_document.lines, vec!["one", "to"]);
+        press(&mut editor, &typed("kAs"));
+        press(&mut editor, &[Key::Esc]);
+        assert!(editor.normal_mode && !editor.options_mode);
+        press(&mut editor, &typed("0ox"));
+        assert_eq!(editor.open_document.lines, vec!["ones", "x", "to"]);
+        assert_eq!(editor.format_status("{mode}"), "INSERT");
+        press(&mut editor, &[Key::Esc, Key::Esc]);
+        assert!(editor.options_mode);
+    }
+
+    #[test]
+    fn go_to_column_clamps_to_line() {
+        let mut editor = editor("0123456789");
+        editor.go_to_column("4");
+        assert_eq!(cursor(&editor), (3, 0));
+        editor.go_to_column("80");
+        assert_eq!(cursor(&editor), (10, 0));
+        editor.go_to_column("zero");
+        assert_eq!(cursor(&editor), (10, 0));
+        assert!(status_message(&editor).is_some());
+    }
+
+    #[test]
+    fn go_to_line_scrolls_and_clamps() {
+        let lines: Vec<String> = (1..=50).map(|n| "x".repeat(n % 7)).collect();
+        let mut editor = editor(&lines.join("\n"));
+        press(&mut editor, &[Key::Down, Key::End]);
+        editor.go_to_line("30");
+        assert_eq!(cursor(&editor), (2, 29)); // Line 30 only has two characters
+        assert!(editor.scroll_position <= 29 && 29 < editor.scroll_position + 9);
+        editor.go_to_line("1000");
+        assert_eq!(cursor(&editor).1, 49);
+        editor.go_to_line("0");
+        assert_eq!(status_message(&editor), Some("Not a line number: 0 "));
+        editor.go_to_line("ten");
+        assert_eq!(cursor(&editor).1, 49);
+    }
+
+    #[test]
+    fn down_past_end_extends_document_when_enabled() {
+        let mut editor = editor("a");
+        press(&mut editor, &[Key::Down; 3]);
+        assert_eq!(editor.open_document.lines, vec!["a"]);
+        assert!(!editor.dirty);
+
+        editor.config.extend_past_end = true;
+        press(&mut editor, &[Key::Down; 12]);
+        assert_eq!(editor.open_document.lines.len(), 13);
+        assert_eq!(cursor(&editor), (0, 12));
+        assert_eq!(editor.scroll_position, 4);
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn observers_hear_changes_moves_and_mode_switches() {
+        let mut editor = editor("ab");
+        let events = Rc::new(RefCell::new(vec![]));
+        editor.add_observer(Box::new(RecordingObserver { events: events.clone() }));
+        press(&mut editor, &[Key::Right, Key::Char('x'), Key::Esc, Key::Esc]);
+        assert_eq!(*events.borrow(), vec!["cursor 0,1", "changed axb", "cursor 0,2", "mode OPTIONS", "mode EDIT"]);
+    }
+
+    #[test]
+    fn literal_line_breaks_split_the_line() {
+        let (mut editor, output) = editor_with_output("ab");
+        press(&mut editor, &[Key::Right, Key::Ctrl('v'), Key::Char('\n'), Key::Ctrl('v'), Key::Ctrl('m'), Key::Ctrl('v'), Key::Ctrl('j')]);
+        assert_eq!(editor.open_document.lines, vec!["a", "", "", "b"]);
+        assert_eq!(cursor(&editor), (0, 3));
+        editor.draw().unwrap();
+        assert!(!output.borrow().contains("^M") && !output.borrow().contains("^J"));
+    }
+
+    #[test]
+    fn idle_quit_counts_down_from_last_key() {
+        let mut editor = editor("");
+        assert_eq!(editor.idle_quit_remaining(), None);
+        editor.config.idle_quit_seconds = 60;
+        assert_eq!(editor.idle_quit_remaining(), Some(60));
+        editor.last_key_time -= std::time::Duration::from_secs(55);
+        assert_eq!(editor.idle_quit_remaining(), Some(5));
+        editor.last_key_time -= std::time::Duration::from_secs(10);
+        assert_eq!(editor.idle_quit_remaining(), Some(0));
+    }
+
+    #[test]
+    fn delete_removes_under_cursor_and_joins_next_line() {
+        let mut editor = editor("abc\ndef");
+        press(&mut editor, &[Key::Right, Key::Delete]);
+        assert_eq!(editor.open_document.lines, vec!["ac", "def"]);
+        press(&mut editor, &[Key::End, Key::Delete]);
+        assert_eq!(editor.open_document.lines, vec!["acdef"]);
+        assert_eq!(cursor(&editor), (2, 0));
+        press(&mut editor, &[Key::End, Key::Delete]);
+        assert_eq!(editor.open_document.lines, vec!["acdef"]);
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn enter_continues_comments_when_enabled() {
+        let mut editor = editor("    // note\n/* start\nlet x;");
+        editor.open_document.path = "main.rs".to_string();
+        press(&mut editor, &[Key::End, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[1], "");
+
+        editor.config.continue_comments = true;
+        press(&mut editor, &[Key::Backspace, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[1], "    // ");
+        press(&mut editor, &typed("more"));
+        press(&mut editor, &[Key::Char('\n'), Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[1..4], ["    // more", "    ", "/* start"]);
+        assert_eq!(cursor(&editor), (4, 2));
+
+        press(&mut editor, &[Key::Down, Key::End, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[4], " * ");
+        press(&mut editor, &typed("x\n"));
+        assert_eq!(editor.open_document.lines[5], " * ");
+        press(&mut editor, &[Key::Up, Key::End, Key::Left, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[4..7], [" * ", " * x", " * "]);
+
+        editor.open_document.path = "notes.txt".to_string();
+        press(&mut editor, &[Key::Up, Key::Up, Key::End, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[4], "");
+    }
+
+    #[test]
+    fn word_under_cursor_and_its_occurrences() {
+        let mut editor = editor("let é_x = é_x + é_xs; (é_x)");
+        assert_eq!(editor.word_under_cursor().as_deref(), Some("let"));
+        press(&mut editor, &[Key::Right; 3]);
+        assert_eq!(editor.word_under_cursor().as_deref(), Some("let"));
+        press(&mut editor, &[Key::Right]);
+        let word = editor.word_under_cursor().unwrap();
+        assert_eq!(word, "é_x");
+        assert_eq!(word_occurrences(&editor.open_document.lines[0], &word).collect::<Vec<_>>(), vec![4, 10, 23]);
+        editor.config.highlight_word = true;
+        editor.draw().unwrap();
+        let mut operators = self::editor("a + b");
+        press(&mut operators, &[Key::Right; 2]);
+        assert_eq!(operators.word_under_cursor(), None);
+    }
+
+    #[test]
+    fn special_files_are_refused() {
+        let mut editor = editor("kept");
+        editor.open_file("/dev/null");
+        assert_eq!(editor.content(), "kept");
+        assert_eq!(status_message(&editor), Some("/dev/null is a device, not a regular file, so it wasn't opened "));
+    }
+
+    #[test]
+    fn binary_files_are_refused() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_binary", std::process::id()));
+        let mut editor = editor("kept");
+        for contents in [&b"\x7fELF\x00\x01"[..], &b"\xff\xfe"[..]] { // NUL bytes, then invalid UTF-8
+            std::fs::write(&path, contents).unwrap();
+            editor.open_file(&path.to_string_lossy());
+            assert_eq!(editor.content(), "kept");
+            assert_eq!(status_message(&editor), Some(format!("Cannot open binary file {} ", path.display()).as_str()));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn undo_and_redo_typing_and_edits() {
+        let mut editor = editor("one\ntwo");
+        press(&mut editor, &[Key::Down, Key::End]);
+        press(&mut editor, &typed(" three"));
+        press(&mut editor, &[Key::Left, Key::Backspace, Key::Char('\n')]);
+        assert_eq!(editor.content(), "one\ntwo thr\ne");
+
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "one\ntwo thre");
+        assert_eq!(cursor(&editor), (7, 1));
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "one\ntwo three");
+        press(&mut editor, &[Key::Ctrl('z')]); // The whole run of typing goes at once
+        assert_eq!(editor.content(), "one\ntwo");
+        assert_eq!(cursor(&editor), (3, 1));
+        press(&mut editor, &[Key::Ctrl('z')]); // Back past set_content
+        assert_eq!(editor.content(), "");
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(status_message(&editor), Some("Already at oldest change "));
+
+        press(&mut editor, &[Key::Ctrl('y'), Key::Ctrl('y'), Key::Ctrl('y')]);
+        assert_eq!(editor.content(), "one\ntwo thre");
+        assert_eq!(cursor(&editor), (7, 1));
+        press(&mut editor, &[Key::Char('!')]); // A new edit drops what could still be redone
+        press(&mut editor, &[Key::Ctrl('y')]);
+        assert_eq!(editor.content(), "one\ntwo thr!e");
+        assert_eq!(status_message(&editor), Some("Already at newest change "));
+    }
+
+    #[test]
+    fn undo_whole_document_changes() {
+        let mut editor = editor("a\n\n\n");
+        editor.trim_trailing_blank_lines();
+        assert_eq!(editor.open_document.lines, vec!["a"]);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["a", "", ""]);
+        editor.set_content("x\ny");
+        press(&mut editor, &[Key::Down, Key::Char('z'), Key::Ctrl('z'), Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["a", "", ""]);
+    }
+
+    #[test]
+    fn incremental_search() {
+        let mut editor = editor("alpha\nbeta\nalphabet\ngamma");
+        press(&mut editor, &[Key::Down, Key::Right, Key::Ctrl('f')]);
+        assert_eq!(editor.mode(), "SEARCH");
+        press(&mut editor, &typed("al"));
+        assert_eq!(cursor(&editor), (0, 2)); // First match after where the search started
+        press(&mut editor, &[Key::Char('\n')]);
+        assert_eq!(cursor(&editor), (0, 0)); // Wrapped round
+        press(&mut editor, &[Key::Char('p'), Key::Backspace]); // Refining starts again from the origin
+        assert_eq!(cursor(&editor), (0, 2));
+        press(&mut editor, &[Key::Char('x')]);
+        assert!(editor.search_failed);
+        assert_eq!(cursor(&editor), (1, 1));
+        press(&mut editor, &[Key::Esc]); // Cancelling goes back
+        assert_eq!(cursor(&editor), (1, 1));
+        assert_eq!(editor.mode(), "EDIT");
+
+        press(&mut editor, &[Key::Ctrl('f')]);
+        press(&mut editor, &typed("gam"));
+        press(&mut editor, &[Key::Right]); // Other keys end the search and act as usual
+        assert_eq!(cursor(&editor), (1, 3));
+        assert_eq!(editor.mode(), "EDIT");
+    }
+
+    #[test]
+    fn editing_multibyte_text_at_every_column() {
+        let line = "héllo 🎉";
+        let chars: Vec<char> = line.chars().collect();
+        for column in 0..=chars.len() {
+            let mut editor = editor(line);
+            press(&mut editor, &vec![Key::Right; column]);
+            press(&mut editor, &[Key::Char('ü')]);
+            let mut expected = chars.clone();
+            expected.insert(column, 'ü');
+            assert_eq!(editor.content(), expected.iter().collect::<String>(), "insert at {}", column);
+            assert_eq!(cursor(&editor), (column + 1, 0));
+
+            press(&mut editor, &[Key::Backspace, Key::Backspace]);
+            if column > 0 {
+                expected.remove(column);
+                expected.remove(column - 1);
+            } else {
+                expected.remove(0);
+            }
+            assert_eq!(editor.content(), expected.iter().collect::<String>(), "backspace at {}", column);
+            assert_eq!(cursor(&editor), (column.saturating_sub(1), 0));
+
+            press(&mut editor, &[Key::Delete, Key::Char('\n')]);
+            let at = column.saturating_sub(1); // Where the cursor is now
+            if at < expected.len() {
+                expected.remove(at);
+            }
+            let (before, after) = expected.split_at(at);
+            assert_eq!(editor.content(), format!("{}\n{}", before.iter().collect::<String>(), after.iter().collect::<String>()), "split at {}", column);
+        }
+    }
+
+    #[test]
+    fn long_lines_scroll_horizontally() {
+        let line: String = ('a'..='z').cycle().take(200).collect();
+        let (mut editor, output) = editor_with_output(&format!("{}\nshort", line));
+        press(&mut editor, &[Key::End]);
+        assert_eq!(editor.h_scroll, 121); // Cursor in the last column
+        let rows: Vec<VisibleRow> = editor.visible_rows().collect();
+        assert_eq!(rows[0].columns, 121..200);
+        assert_eq!(rows[1].columns, 5..5); // Nothing of the short line is in view
+        assert_eq!(editor.format_status("{col}"), "201");
+
+        press(&mut editor, &[Key::Left; 80]);
+        assert_eq!(editor.h_scroll, 120);
+        press(&mut editor, &[Key::Down]); // Clamped to the short line, which scrolls back into view when drawn
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert_eq!(editor.h_scroll, 5);
+        assert!(output.borrow().starts_with(&format!("<{}", &line[6..84]))); // The marker takes the first column
+        press(&mut editor, &[Key::Home]);
+        assert_eq!(editor.h_scroll, 0);
+    }
+
+    #[test]
+    fn edits_that_shrink_the_document_keep_the_cursor_inside_it() {
+        let lines: Vec<String> = (1..=30).map(|n| format!("line {}", n)).collect();
+        let mut editor = editor(&lines.join("\n"));
+        press(&mut editor, &[Key::Down; 25]);
+        press(&mut editor, &[Key::End]);
+        assert!(editor.scroll_position > 0);
+        editor.record_edit(2..30, false, |editor| editor.open_document.lines.truncate(2)); // Like a filter dropping most lines
+        assert_eq!(editor.scroll_position, 0);
+        assert_eq!(cursor(&editor), (6, 1));
+        editor.draw().unwrap();
+        press(&mut editor, &[Key::Up, Key::Down, Key::Down, Key::Backspace]);
+        assert_eq!(editor.open_document.lines, vec!["line 1", "line "]);
+    }
+
+    #[test]
+    fn shrinking_the_terminal_keeps_the_cursor_on_screen() {
+        let lines: Vec<String> = (1..=50).map(|n| n.to_string()).collect();
+        let mut editor = editor(&lines.join("\n"));
+        press(&mut editor, &[Key::Down; 8]);
+        editor.terminal.height = 5;
+        editor.clamp_viewport();
+        assert_eq!(editor.terminal.get_cursor_position().y, 3); // Last row above the status bar
+        assert_eq!(cursor(&editor), (0, 8));
+        for (width, height) in [(1, 1), (0, 0), (3, 2)] { // Tiny sizes draw without underflowing
+            editor.terminal.width = width;
+            editor.terminal.height = height;
+            editor.clamp_viewport();
+            editor.draw().unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_save_keeps_running_and_dirty() {
+        let mut editor = editor("");
+        editor.open_document.path = "/proc/bim_cannot_write_here".to_string();
+        editor.config.create_parent_dirs = false;
+        press(&mut editor, &typed("x"));
+        press(&mut editor, &[Key::Esc, Key::Char('a')]);
+        assert!(editor.running);
+        assert!(editor.dirty);
+        assert!(status_message(&editor).unwrap().starts_with("Could not save /proc/bim_cannot_write_here: "));
+    }
+
+    #[test]
+    fn line_number_gutter() {
+        let lines: Vec<String> = (1..=12).map(|n| format!("line {}", n)).collect();
+        let (mut editor, output) = editor_with_output(&lines.join("\n"));
+        press(&mut editor, &[Key::Esc, Key::Char('l'), Key::Down, Key::End]);
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with(" 1 line 1 2 line 2"));
+        assert_eq!(editor.terminal.x_offset, 3);
+        assert_eq!(editor.text_width(), 77);
+        assert_eq!(cursor(&editor), (6, 1)); // The document column, the gutter only shifts it on screen
+        press(&mut editor, &[Key::Esc, Key::Char('l')]);
+        editor.draw().unwrap();
+        assert_eq!(editor.terminal.x_offset, 0);
+    }
+
+    #[test]
+    fn page_down_and_up_move_a_screen() {
+        let lines: Vec<String> = (1..=30).map(|n| format!("line {}", n)).collect();
+        let mut editor = editor(&lines.join("\n"));
+        press(&mut editor, &[Key::Down, Key::End, Key::PageDown]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (9, (6, 10)));
+        press(&mut editor, &[Key::PageDown]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (18, (6, 19)));
+        press(&mut editor, &[Key::PageDown]); // Stops with the last line at the bottom
+        assert_eq!((editor.scroll_position, cursor(&editor)), (21, (6, 28)));
+        press(&mut editor, &[Key::PageDown]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (21, (6, 29)));
+        press(&mut editor, &[Key::PageUp, Key::PageUp, Key::PageUp]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (0, (6, 2)));
+        press(&mut editor, &[Key::PageUp]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (0, (6, 0)));
+
+        let mut short = self::editor("a\nbb");
+        press(&mut short, &[Key::PageDown]);
+        assert_eq!((short.scroll_position, cursor(&short)), (0, (0, 1)));
+    }
+
+    #[test]
+    fn ensure_trailing_newline_on_save() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_ensure_newline", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        for (text, ensure, expected) in [("a", false, "a"), ("a", true, "a\n"), ("a\n", true, "a\n"), ("", true, "")] {
+            std::fs::write(&path, text).unwrap();
+            let mut editor = editor("");
+            editor.config.ensure_trailing_newline = ensure;
+            editor.open_file(&path);
+            assert!(editor.save());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), expected, "{:?}", text);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tabs_draw_to_the_next_tab_stop() {
+        let (mut editor, output) = editor_with_output("a\tb\n\tc\n  d");
+        editor.config.tab_width = 4;
+        press(&mut editor, &[Key::Right, Key::Right]);
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("a   b    c  d"));
+        assert_eq!(editor.cursor_display_column(), 4); // On the b, past the tab
+        press(&mut editor, &[Key::Down]);
+        assert_eq!(cursor(&editor), (1, 1)); // On the c, lined up on screen
+        press(&mut editor, &[Key::Down]);
+        assert_eq!(cursor(&editor), (3, 2)); // End of a shorter line
+        press(&mut editor, &[Key::Up, Key::Left]);
+        assert_eq!(editor.cursor_display_column(), 0); // Left over the tab takes its whole width
+
+        editor.config.expand_tabs = true;
+        press(&mut editor, &[Key::Right, Key::Right, Key::Char('\t')]);
+        assert_eq!(editor.open_document.lines[1], "\tc   ");
+        assert_eq!(display_column("ab\tc", 4, 4), 5);
+        assert_eq!(column_at_display("ab\tc", 3, 4), 2);
+    }
+
+    #[test]
+    fn draw_only_rewrites_changed_rows() {
+        let (mut editor, output) = editor_with_output("one\ntwo\nthree");
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("onetwothree~"));
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(!output.borrow().contains("one") && !output.borrow().contains('~')); // Only the status bar
+        press(&mut editor, &[Key::Down, Key::End]);
+        press(&mut editor, &typed("s"));
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("twos"));
+        assert!(!output.borrow().contains("one") && !output.borrow().contains("three"));
+
+        editor.redraw_all();
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("onetwosthree~"));
+    }
+
+    #[test]
+    fn copy_cut_and_paste_a_selection() {
+        let mut editor = editor("one\ntwo\nthree");
+        press(&mut editor, &[Key::Right, Key::Ctrl('b'), Key::Down, Key::Right, Key::Ctrl('c')]);
+        assert_eq!(editor.clipboard, "ne\ntw");
+        assert!(editor.selection_anchor.is_none());
+        press(&mut editor, &[Key::Down, Key::End, Key::Ctrl('u')]);
+        assert_eq!(editor.open_document.lines, vec!["one", "two", "threene", "tw"]);
+        assert_eq!(cursor(&editor), (2, 3));
+
+        press(&mut editor, &[Key::Ctrl('b'), Key::Up, Key::Up, Key::Up, Key::Home, Key::Ctrl('x')]); // Selecting backwards
+        assert_eq!(editor.clipboard, "one\ntwo\nthreene\ntw");
+        assert_eq!(editor.open_document.lines, vec![""]);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["one", "two", "threene", "tw"]);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["one", "two", "three"]);
+
+        press(&mut editor, &[Key::Ctrl('b'), Key::Right, Key::Char('x')]); // Typing drops the selection
+        assert!(editor.selection_anchor.is_none());
+        press(&mut editor, &[Key::Ctrl('c')]);
+        assert_eq!(status_message(&editor), Some("Nothing selected (Ctrl+B to start selecting) "));
+    }
+
+    #[test]
+    fn cutting_a_selection_that_starts_above_the_screen() {
+        let lines: Vec<String> = (1..=30).map(|n| n.to_string()).collect();
+        let mut editor = editor(&lines.join("\n"));
+        press(&mut editor, &[Key::Down, Key::Ctrl('b')]);
+        press(&mut editor, &[Key::Down; 20]);
+        assert!(editor.scroll_position > 0);
+        press(&mut editor, &[Key::Ctrl('x')]);
+        assert_eq!(editor.clipboard, lines[1..21].join("\n") + "\n");
+        assert_eq!(editor.open_document.lines.len(), 10);
+        assert_eq!(editor.open_document.lines[1], "22");
+        assert_eq!(cursor(&editor), (0, 1));
+        assert_eq!(editor.scroll_position, 1);
+    }
+
+    #[test]
+    fn auto_indent_copies_the_split_lines_indentation() {
+        let mut editor = editor("\t  if x {\n");
+        press(&mut editor, &[Key::End, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[1], ""); // Off by default
+        press(&mut editor, &[Key::Backspace, Key::Esc, Key::Char('i'), Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[1], "\t  ");
+        assert_eq!(cursor(&editor), (3, 1));
+        press(&mut editor, &[Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines[2], "\t  "); // A second Enter keeps it, unlike a comment prefix
+
+        let mut editor = self::editor("    foo  bar");
+        editor.config.auto_indent = true;
+        press(&mut editor, &[Key::End, Key::Left, Key::Left, Key::Left, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines, vec!["    foo  ", "    bar"]);
+        press(&mut editor, &[Key::Up, Key::Home, Key::Right, Key::Right, Key::Char('\n')]);
+        assert_eq!(editor.open_document.lines, vec!["  ", "    foo  ", "    bar"]); // Inside the indentation only what's before the cursor
+    }
+
+    #[test]
+    fn word_motion_crosses_punctuation_and_lines() {
+        let mut editor = editor("let foo_1 = bar(x);\n  \n  baz");
+        press(&mut editor, &[Key::Alt('f')]);
+        assert_eq!(cursor(&editor), (4, 0));
+        press(&mut editor, &[Key::Alt('f')]);
+        assert_eq!(cursor(&editor), (12, 0)); // Over " = "
+        press(&mut editor, &[Key::Alt('f'), Key::Alt('f')]);
+        assert_eq!(cursor(&editor), (2, 2)); // Over ");" and the blank line
+        press(&mut editor, &[Key::Alt('f')]);
+        assert_eq!(cursor(&editor), (5, 2)); // End of the document
+        press(&mut editor, &[Key::Alt('b'), Key::Alt('b')]);
+        assert_eq!(cursor(&editor), (16, 0));
+        press(&mut editor, &[Key::Left, Key::Alt('b')]);
+        assert_eq!(cursor(&editor), (12, 0)); // From inside a word to its start
+        press(&mut editor, &[Key::Home, Key::Alt('b')]);
+        assert_eq!(cursor(&editor), (0, 0));
+        assert_eq!(unparsed_key(b"\x1b[1;5C"), Some(Key::Alt('f')));
+        assert_eq!(unparsed_key(b"\x1b[1;5D"), Some(Key::Alt('b')));
+    }
+
+    #[test]
+    fn replace_asks_about_each_match_and_wraps_round() {
+        let mut editor = editor("cat cat\ncat dog cat");
+        press(&mut editor, &[Key::Down, Key::Right]);
+        let mut answers = vec![Key::Char('y'), Key::Char('n'), Key::Char('a')].into_iter();
+        let result = editor.replace_matches("cat", "kitten", |_| Ok(answers.next().unwrap()));
+        assert_eq!(result.unwrap(), (4, 3));
+        assert_eq!(editor.open_document.lines, vec!["cat kitten", "kitten dog kitten"]); // The match the cursor started inside comes last
+        assert!(editor.dirty);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["cat cat", "cat dog cat"]); // Undone in one go
+
+        let mut editor = self::editor("aa aa");
+        let result = editor.replace_matches("a", "aa", |_| Ok(Key::Char('a')));
+        assert_eq!(result.unwrap(), (4, 4)); // The replacement containing the query isn't matched again
+        assert_eq!(editor.open_document.lines, vec!["aaaa aaaa"]);
+        let result = editor.replace_matches("x", "y", |_| Ok(Key::Char('y')));
+        assert_eq!(result.unwrap(), (0, 0));
+        let result = editor.replace_matches("aaaa", "b", |_| Ok(Key::Esc));
+        assert_eq!(result.unwrap(), (0, 0));
+        assert_eq!(editor.open_document.lines, vec!["aaaa aaaa"]);
+    }
+
+    #[test]
+    fn multiple_files_open_in_their_own_buffers() {
+        let directory = std::env::temp_dir();
+        let paths: Vec<String> = ["one", "two", "three"].iter()
+            .map(|name| {
+                let path = directory.join(format!("bim_test_{}_buffer_{}", std::process::id(), name));
+                std::fs::write(&path, name).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let mut editor = editor("");
+        editor.open_files(&paths);
+        assert_eq!(editor.content(), "one");
+        assert_eq!(editor.format_status("{buffers}"), format!("[1/3] {} ", paths[0]));
+        press(&mut editor, &[Key::End]);
+        press(&mut editor, &typed("!"));
+        press(&mut editor, &[Key::Alt('p')]); // Round to the last
+        assert_eq!(editor.content(), "three");
+        assert_eq!(cursor(&editor), (0, 0));
+        assert!(!editor.dirty);
+        press(&mut editor, &[Key::Alt('n')]);
+        assert_eq!(editor.content(), "one!");
+        assert_eq!(cursor(&editor), (4, 0)); // Where it was left
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "one"); // With its own undo history
+        press(&mut editor, &typed("?"));
+
+        press(&mut editor, &[Key::Alt('n')]);
+        press(&mut editor, &[Key::Esc, Key::Char('q')]);
+        assert!(editor.quit_pending); // Another file has unsaved changes
+        press(&mut editor, &[Key::Char('x'), Key::Char('a')]); // Cancel, then save and exit
+        assert!(editor.running);
+        assert_eq!(status_message(&editor), Some(format!("{} has unsaved changes too ", paths[0]).as_str()));
+        assert!(editor.save_all());
+        assert_eq!(editor.content(), "two");
+        assert_eq!(std::fs::read_to_string(&paths[0]).unwrap(), "one?");
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_only_ignores_edits_but_allows_movement() {
+        let mut editor = editor("one\ntwo");
+        editor.read_only = true;
+        press(&mut editor, &[Key::Down, Key::Right]);
+        press(&mut editor, &typed("x"));
+        press(&mut editor, &[Key::Backspace, Key::Delete, Key::Char('\n'), Key::Ctrl('u'), Key::Down]);
+        assert_eq!(editor.open_document.lines, vec!["one", "two"]);
+        assert_eq!(cursor(&editor), (1, 1)); // Down on the last line doesn't grow the document
+        assert!(!editor.dirty);
+        assert_eq!(status_message(&editor), Some("Read-only (v in the options menu allows editing) "));
+        assert!(!editor.save());
+        assert!(editor.format_status("{readonly}").contains("[readonly]"));
+        press(&mut editor, &[Key::Esc, Key::Char('v'), Key::Char('x')]); // v toggles it off again
+        assert_eq!(editor.open_document.lines, vec!["one", "txwo"]);
+        assert!(editor.format_status("{readonly}").is_empty());
+    }
+
+    #[test]
+    fn ctrl_o_jumps_between_matching_brackets() {
+        let lines: Vec<String> = (0..20).map(|_| "x".to_string()).collect();
+        let mut editor = editor(&format!("fn a(b[0]) {{\n{}\n    if (c) {{ d }}\n}}", lines.join("\n")));
+        press(&mut editor, &[Key::End, Key::Left, Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (0, 22)); // Past the inner pair, scrolled down to show it
+        assert!(editor.scroll_position <= 22 && 22 < editor.scroll_position + 9);
+        press(&mut editor, &[Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (11, 0));
+        assert_eq!(editor.scroll_position, 0);
+        press(&mut editor, &[Key::Home, Key::Right, Key::Right, Key::Right, Key::Right, Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (9, 0)); // Round brackets skip the square ones inside
+        press(&mut editor, &[Key::Left, Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (6, 0));
+        press(&mut editor, &[Key::Left, Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (5, 0));
+        assert_eq!(status_message(&editor), Some("Not on a bracket "));
+        let mut editor = self::editor("(a [b) c");
+        press(&mut editor, &[Key::Right, Key::Right, Key::Right, Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (3, 0));
+        assert_eq!(status_message(&editor), Some("No bracket matches [ "));
+    }
+
+    #[test]
+    fn t_toggles_trimming_whitespace_on_save() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_trim_whitespace", std::process::id()));
+        let mut editor = editor("a  \n \t");
+        editor.open_document.path = path.to_string_lossy().to_string();
+        press(&mut editor, &[Key::Esc, Key::Char('t')]);
+        assert!(editor.save());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\n");
+        assert_eq!(editor.open_document.lines, vec!["a  ", " \t"]); // Left alone in the buffer
+        editor.config.trim_whitespace_in_buffer = true;
+        assert!(editor.save());
+        assert_eq!(editor.open_document.lines, vec!["a", ""]);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["a  ", " \t"]);
+        press(&mut editor, &[Key::Esc, Key::Char('t')]);
+        assert!(editor.save());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a  \n \t");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells() {
+        let (mut editor, output) = editor_with_output("日本語 ok\na\x01bc");
+        press(&mut editor, &[Key::Right, Key::Right]);
+        assert_eq!(cursor(&editor), (2, 0));
+        assert_eq!(editor.cursor_display_column(), 4);
+        press(&mut editor, &[Key::Down]);
+        assert_eq!(cursor(&editor), (3, 1)); // On the c, past the two cells of ^A
+        press(&mut editor, &[Key::Up]);
+        assert_eq!(cursor(&editor), (2, 0));
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("日本語 ok"));
+
+        editor.terminal.width = 4; // The edges cut wide characters in half, which are drawn as spaces
+        editor.h_scroll = 3;
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("<語>"));
+        editor.line_markers = false;
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with(" 語 "));
+        editor.line_markers = true;
+        press(&mut editor, &[Key::Home]);
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("日 >"));
+        press(&mut editor, &[Key::Right, Key::Right]);
+        editor.draw().unwrap();
+        assert_eq!(editor.h_scroll, 2); // Far enough to show both cells of 語
+        editor.redraw_all();
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("<  >")); // The markers take a cell each of 本 and 語, leaving their halves blank
+        assert_eq!(column_at_display("日本語", 3, 8), 1);
+    }
+
+    #[test]
+    fn large_files_open_lazily_until_edited() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_lazy_open", std::process::id()));
+        let text: String = (1..=5000).map(|n| format!("{}\n", n)).collect();
+        std::fs::write(&path, &text).unwrap();
+        let (mut editor, output) = editor_with_output("");
+        editor.config.lazy_load_threshold = 1000;
+        editor.open_file(&path.to_string_lossy());
+        assert!(editor.open_document.is_lazy());
+        editor.go_to_line("4000");
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().contains("39994000"));
+        press(&mut editor, &[Key::Down, Key::End]);
+        assert_eq!(cursor(&editor), (4, 4000)); // Lines below the view are read before moving onto them
+        press(&mut editor, &typed("!"));
+        assert!(!editor.open_document.is_lazy());
+        assert_eq!(editor.open_document.lines[4000], "4001!");
+        assert_eq!(editor.open_document.lines.len(), 5000);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.content(), text);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_holds_in_vim_normal_mode() {
+        let mut editor = editor("abc");
+        editor.config.vim_mode = true;
+        editor.normal_mode = true;
+        editor.read_only = true;
+        press(&mut editor, &[Key::Delete, Key::Ctrl('t'), Key::Backspace, Key::Ctrl('x'), Key::Ctrl('u'), Key::Char('x')]);
+        assert_eq!(editor.open_document.lines, vec!["abc"]);
+        assert!(!editor.dirty);
+        press(&mut editor, &[Key::Char('l'), Key::Right]);
+        assert_eq!(cursor(&editor), (2, 0)); // Motions still work
+        press(&mut editor, &[Key::Char('i'), Key::Char('z')]); // Insert mode refuses typing too
+        assert_eq!(editor.open_document.lines, vec!["abc"]);
+        assert_eq!(status_message(&editor), Some("Read-only (v in the options menu allows editing) "));
+    }
+
+    #[test]
+    fn undo_takes_back_a_long_typing_run_whole() {
+        let mut editor = editor("start");
+        press(&mut editor, &[Key::End, Key::Char('\n')]);
+        press(&mut editor, &typed(&"x".repeat(1100)));
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["start", ""]);
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.open_document.lines, vec!["start"]);
+    }
+
+    #[test]
+    fn bracket_and_word_scans_read_a_lazy_document() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_lazy_scan", std::process::id()));
+        let mut lines = vec!["{".to_string()];
+        lines.extend(std::iter::repeat_n(String::new(), 3999));
+        lines.push("} end".to_string());
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        let mut editor = editor("");
+        editor.config.lazy_load_threshold = 1000;
+        editor.open_file(&path.to_string_lossy());
+        editor.draw().unwrap();
+        assert!(editor.open_document.is_lazy());
+        press(&mut editor, &[Key::Ctrl('o')]);
+        assert_eq!(cursor(&editor), (0, 4000)); // Past the lines read for the view
+        assert!(!editor.open_document.is_lazy());
+
+        editor.open_file(&path.to_string_lossy());
+        editor.draw().unwrap();
+        press(&mut editor, &[Key::Right, Key::Alt('f')]);
+        assert_eq!(cursor(&editor), (2, 4000)); // The next word is on the last line, after the blank lines
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn options_help_pages_fit_the_terminal() {
+        let mut editor = editor("");
+        press(&mut editor, &[Key::Esc]);
+        let mut shown = String::new();
+        let first = editor.options_help();
+        assert!(first.starts_with("[Options 1/"));
+        loop {
+            let page = editor.options_help();
+            assert!(page.chars().count() <= 80, "{}", page);
+            shown.push_str(&page);
+            press(&mut editor, &[Key::Char(' ')]);
+            if editor.options_help() == first {
+                break;
+            }
+        }
+        assert!(editor.options_mode);
+        assert!(OPTIONS_HELP.iter().all(|entry| shown.contains(entry)));
+        press(&mut editor, &[Key::Char(' '), Key::Esc, Key::Esc]);
+        assert_eq!(editor.options_help(), first); // Reopening starts on the first page again
+    }
+
+    #[test]
+    fn vim_delete_yank_and_put() {
+        let mut editor = editor("one two\nthree\nfour");
+        editor.config.vim_mode = true;
+        editor.normal_mode = true;
+        press(&mut editor, &typed("dw"));
+        assert_eq!(editor.open_document.lines, vec!["two", "three", "four"]);
+        assert_eq!(editor.clipboard, "one ");
+        press(&mut editor, &typed("dw")); // The last word stops at the end of the line
+        assert_eq!(editor.open_document.lines, vec!["", "three", "four"]);
+        press(&mut editor, &typed("jyyjp"));
+        assert_eq!(editor.open_document.lines, vec!["", "three", "four", "three"]);
+        assert_eq!(cursor(&editor), (0, 3));
+        press(&mut editor, &typed("dd"));
+        assert_eq!(editor.open_document.lines, vec!["", "three", "four"]);
+        assert_eq!(cursor(&editor), (0, 2));
+        press(&mut editor, &typed("kkdd"));
+        assert_eq!(editor.open_document.lines, vec!["three", "four"]);
+        press(&mut editor, &typed("$y0p")); // Yanked text pastes after the cursor
+        assert_eq!(editor.open_document.lines, vec!["tthreehree", "four"]);
+        press(&mut editor, &typed("dzx")); // Another key cancels the operator
+        assert_eq!(editor.open_document.lines, vec!["tthreeree", "four"]);
+        editor.read_only = true;
+        press(&mut editor, &typed("ddp"));
+        assert_eq!(editor.open_document.lines, vec!["tthreeree", "four"]);
+    }
+
+    #[test]
+    fn lock_file_can_open_read_only() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_locked_open", std::process::id()));
+        let lock = std::env::temp_dir().join(format!("bim_test_{}_locked_open.lock", std::process::id()));
+        std::fs::write(&path, "text\n").unwrap();
+        std::fs::write(&lock, "").unwrap();
+        let mut editor = editor("");
+        editor.config.lock_files = "{name}.lock".to_string();
+        editor.open_file(&path.to_string_lossy());
+        assert!(!editor.read_only); // Only a warning by default
+        editor.config.read_only_when_locked = true;
+        editor.open_file(&path.to_string_lossy());
+        assert!(editor.read_only);
+        assert!(status_message(&editor).is_some_and(|message| message.contains("opened read-only")));
+        std::fs::remove_file(&lock).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn select_all_copies_and_deletes_the_whole_document() {
+        let mut editor = editor("one\ntwo\nthree");
+        press(&mut editor, &[Key::Down, Key::Ctrl('a'), Key::Ctrl('c')]);
+        assert_eq!(editor.clipboard, "one\ntwo\nthree");
+        press(&mut editor, &[Key::Ctrl('a'), Key::Ctrl('x')]);
+        assert_eq!(editor.open_document.lines, vec![""]);
+        assert_eq!(cursor(&editor), (0, 0));
+        press(&mut editor, &[Key::Ctrl('a'), Key::Ctrl('x')]); // Nothing to take from an empty buffer
+        assert_eq!(editor.open_document.lines, vec![""]);
+        assert_eq!(editor.clipboard, "");
+        press(&mut editor, &[Key::Ctrl('u')]);
+        assert_eq!(editor.open_document.lines, vec![""]);
+    }
+
+    #[test]
+    fn search_can_center_the_match() {
+        let lines: Vec<String> = (1..=50).map(|n| format!("line {}", n)).collect();
+        let mut editor = editor(&lines.join("\n"));
+        editor.config.search_centers_match = true; // 9 rows of text, so the middle third is rows 3 to 5
+        press(&mut editor, &[Key::Ctrl('f')]);
+        press(&mut editor, &typed("line 2"));
+        assert_eq!((editor.scroll_position, cursor(&editor)), (0, (0, 1))); // Can't scroll above the first line
+        press(&mut editor, &typed("\n"));
+        assert_eq!((editor.scroll_position, cursor(&editor)), (15, (0, 19)));
+        press(&mut editor, &typed("\n")); // The next line down is still in the middle third
+        assert_eq!((editor.scroll_position, cursor(&editor)), (15, (0, 20)));
+        press(&mut editor, &typed("\n"));
+        assert_eq!((editor.scroll_position, cursor(&editor)), (17, (0, 21)));
+        press(&mut editor, &[Key::Backspace, Key::Char('4'), Key::Char('9')]);
+        assert_eq!((editor.scroll_position, cursor(&editor)), (41, (0, 48))); // The last line stays at the bottom
+    }
+
+    #[test]
+    fn ctrl_caret_switches_back_to_the_previous_file() {
+        let directory = std::env::temp_dir();
+        let paths: Vec<String> = ["one", "two", "three"].iter()
+            .map(|name| {
+                let path = directory.join(format!("bim_test_{}_alternate_{}", std::process::id(), name));
+                std::fs::write(&path, name).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let mut editor = editor("");
+        editor.open_files(&paths);
+        press(&mut editor, &[Key::Ctrl('6')]); // Nothing before the first file
+        assert_eq!(editor.content(), "one");
+        press(&mut editor, &[Key::Alt('p'), Key::Ctrl('6')]);
+        assert_eq!(editor.content(), "one");
+        press(&mut editor, &[Key::Ctrl('6')]);
+        assert_eq!(editor.content(), "three");
+        press(&mut editor, &typed("!"));
+        assert!(editor.save_all());
+        press(&mut editor, &[Key::Ctrl('6')]); // Saving every file doesn't change which one was before
+        assert_eq!(editor.content(), "one");
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn registers_hold_text_apart_from_the_clipboard() {
+        let mut editor = editor("one two");
+        press(&mut editor, &[Key::Ctrl('b'), Key::Alt('f'), Key::Alt('r'), Key::Char('a'), Key::Ctrl('c')]);
+        assert_eq!(editor.registers[&'a'], vec!["one "]);
+        assert_eq!(editor.clipboard, "");
+        press(&mut editor, &[Key::Ctrl('b'), Key::End, Key::Ctrl('c')]); // No register chosen, so the clipboard
+        assert_eq!(editor.clipboard, "two");
+        press(&mut editor, &[Key::Alt('r'), Key::Char('a'), Key::Ctrl('u'), Key::Ctrl('u')]);
+        assert_eq!(editor.content(), "one twoone two");
+        press(&mut editor, &[Key::Alt('r'), Key::Char('7'), Key::Left, Key::Ctrl('u')]); // Moving uses up the choice
+        assert_eq!(editor.content(), "one twoone twtwoo");
+        assert!(!editor.registers.contains_key(&'7'));
+
+        let mut editor = self::editor("first\nsecond");
+        editor.config.vim_mode = true;
+        editor.normal_mode = true;
+        press(&mut editor, &typed("\"byyjyy\"bp"));
+        assert_eq!(editor.open_document.lines, vec!["first", "second", "first"]);
+        press(&mut editor, &typed("p"));
+        assert_eq!(editor.open_document.lines, vec!["first", "second", "first", "second"]);
+    }
+
+    #[test]
+    fn large_cuts_can_skip_the_confirmation() {
+        let lines: Vec<String> = (1..=30).map(|n| n.to_string()).collect();
+        let mut editor = editor(&lines.join("\n"));
+        editor.config.confirm_cut_lines = 5;
+        press(&mut editor, &[Key::Ctrl('b'), Key::Down, Key::Down, Key::Down, Key::Down, Key::Ctrl('x')]); // Five lines, at the limit
+        assert_eq!(editor.open_document.lines.len(), 26);
+        press(&mut editor, &[Key::Ctrl('a'), Key::Alt('x')]); // Far more, but forced
+        assert_eq!(editor.open_document.lines, vec![""]);
+        assert_eq!(editor.clipboard, lines[4..].join("\n"));
+        press(&mut editor, &[Key::Ctrl('z')]);
+        editor.read_only = true;
+        press(&mut editor, &[Key::Ctrl('a'), Key::Alt('x')]);
+        assert_eq!(editor.open_document.lines.len(), 26);
+    }
+
+    #[test]
+    fn reindent_shifts_lines_to_the_new_indentation() {
+        assert_eq!(reindent("    if x {\n        y();\n\n    }\n", "\t"), "if x {\n\t    y();\n\n\t}\n");
+        assert_eq!(reindent("a\n  b", "    "), "a\n      b");
+        assert_eq!(reindent("    a\n  b\n      c", ""), "a\nb\n  c"); // b was indented less than a
+    }
+
+    #[test]
+    fn reindenting_paste_matches_the_cursor_line() {
+        let mut editor = editor("fn f() {\n    x();\n}");
+        editor.config.auto_indent = true;
+        press(&mut editor, &[Key::Ctrl('a'), Key::Ctrl('c')]);
+        press(&mut editor, &[Key::Up, Key::End, Key::Char('\n'), Key::Alt('u')]);
+        assert_eq!(editor.open_document.lines, vec!["fn f() {", "    x();", "    fn f() {", "        x();", "    }", "}"]);
+        press(&mut editor, &[Key::Ctrl('u')]); // Plain paste is as it was
+        assert_eq!(editor.open_document.lines[4..], ["    }fn f() {", "    x();", "}", "}"]);
+    }
+
+    #[test]
+    fn read_only_draws_tabs_at_the_display_tab_width() {
+        let (mut editor, output) = editor_with_output("a\tb");
+        editor.config.tab_width = 4;
+        editor.config.display_tab_width = 2;
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("a   b"));
+        editor.read_only = true;
+        press(&mut editor, &[Key::End]);
+        output.borrow_mut().clear();
+        editor.draw().unwrap();
+        assert!(output.borrow().starts_with("a b"));
+        assert_eq!(editor.cursor_display_column(), 3);
+    }
+
+    #[test]
+    fn config_errors_show_in_the_status_bar() {
+        let mut config = Config::default();
+        config.apply("tab_width = wide\nwrap = yes");
+        let terminal = Terminal::new(Box::new(TestBackend { width: 80, height: 10, output: Rc::new(RefCell::new(String::new())) }));
+        let editor = Editor::new(terminal, config);
+        assert_eq!(status_message(&editor), Some("~/.bimrc line 1: Expected a number, got wide (and 1 more) "));
+    }
+
+    #[test]
+    fn undo_takes_back_a_typed_word_unless_typing_paused() {
+        let mut editor = editor("");
+        press(&mut editor, &typed("hello"));
+        press(&mut editor, &[Key::Ctrl('z')]); // The whole word goes in one step
+        assert_eq!(editor.content(), "");
+
+        press(&mut editor, &typed("hel"));
+        editor.last_insert_time -= std::time::Duration::from_millis(editor.config.undo_coalesce_ms + 1);
+        press(&mut editor, &typed("lo"));
+        press(&mut editor, &[Key::Ctrl('z')]); // Only what was typed after the pause
+        assert_eq!(editor.content(), "hel");
+        press(&mut editor, &[Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "");
+    }
+
+    #[test]
+    fn undo_limit_forgets_the_oldest_changes() {
+        let mut editor = editor("");
+        editor.config.undo_limit = 2;
+        press(&mut editor, &typed("one"));
+        press(&mut editor, &[Key::Char('\n')]);
+        press(&mut editor, &typed("two"));
+        press(&mut editor, &[Key::Ctrl('z'), Key::Ctrl('z'), Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "one"); // Typing "one" was forgotten
+        assert_eq!(status_message(&editor), Some("Already at oldest change "));
+    }
+
+    #[test]
+    fn clicks_move_the_cursor_and_select_words_and_lines() {
+        let mut editor = editor("let answer = 42;\n\tfoo_bar(x)");
+        editor.click(6, 0);
+        assert_eq!(cursor(&editor), (6, 0));
+        assert!(editor.selection_anchor.is_none());
+        editor.click(6, 0); // Double click inside "answer"
+        assert_eq!(editor.selection(), Some(((0, 4), (0, 10))));
+        editor.click(6, 0); // Triple click
+        assert_eq!(editor.selection(), Some(((0, 0), (0, 16))));
+
+        editor.click(11, 1); // Past the tab, on the second o of foo_bar
+        editor.click(11, 1);
+        assert_eq!(editor.selection(), Some(((1, 1), (1, 8)))); // Underscores belong to the word, as for word motion
+        press(&mut editor, &[Key::Ctrl('c')]);
+        assert_eq!(editor.clipboard, "foo_bar");
+
+        editor.click(11, 0); // On the = sign, not a word
+        editor.click(11, 0);
+        assert!(editor.selection_anchor.is_none());
+        assert_eq!(cursor(&editor), (11, 0));
+    }
+
+    #[test]
+    fn slow_or_moved_clicks_start_over() {
+        let mut editor = editor("one two\nthree");
+        editor.click(1, 0);
+        editor.click(5, 0); // Somewhere else
+        assert!(editor.selection_anchor.is_none());
+        if let Some((time, _, _)) = &mut editor.last_click {
+            *time -= std::time::Duration::from_millis(DOUBLE_CLICK_MS);
+        }
+        editor.click(5, 0);
+        assert!(editor.selection_anchor.is_none());
+        assert_eq!(cursor(&editor), (5, 0));
+        editor.click(3, 5); // Below the last line
+        assert_eq!(cursor(&editor), (5, 0));
+    }
+
+    #[test]
+    fn clicks_allow_for_the_gutter_and_scrolling() {
+        let (mut editor, _) = editor_with_output(&(1..=30).map(|n| format!("line {}", n)).collect::<Vec<_>>().join("\n"));
+        editor.line_numbers = true;
+        editor.scroll_position = 20;
+        editor.draw().unwrap(); // Lays out the gutter
+        let gutter = editor.terminal.x_offset;
+        assert!(gutter > 0);
+        editor.click(gutter + 5, 2);
+        editor.click(gutter + 5, 2);
+        assert_eq!(editor.selection(), Some(((22, 5), (22, 7))));
+        assert_eq!(editor.open_document.text_between((22, 5), (22, 7)), "23");
+    }
+
+    #[test]
+    fn vertical_movement_keeps_the_screen_column_across_tabs() {
+        let mut editor = editor("\tx = 1;\n    y = 2;\n  \tz\n\t\tdeep\nab");
+        editor.config.tab_width = 4;
+        press(&mut editor, &[Key::Right]);
+        assert_eq!(editor.cursor_display_column(), 4);
+        let mut columns = vec![];
+        for key in [Key::Down, Key::Down, Key::Down, Key::Down, Key::Up, Key::Up, Key::Up, Key::Up] {
+            press(&mut editor, &[key]);
+            columns.push((cursor(&editor), editor.cursor_display_column()));
+        }
+        assert_eq!(columns, vec![
+            ((4, 1), 4), // Past four spaces
+            ((3, 2), 4), // Past two spaces and a tab
+            ((1, 3), 4), // On the second of two tabs, which starts at the same screen column
+            ((2, 4), 2), // Short line, the end is as close as it gets
+            ((1, 3), 4), // Back under the same spot on the way up
+            ((3, 2), 4),
+            ((4, 1), 4),
+            ((1, 0), 4),
+        ]);
+    }
+
+    #[test]
+    fn undo_back_to_the_save_leaves_nothing_unsaved() {
+        let path = std::env::temp_dir().join(format!("bim_test_{}_undo_to_save", std::process::id()));
+        let mut editor = editor("a");
+        editor.open_document.path = path.to_string_lossy().to_string();
+        press(&mut editor, &[Key::End, Key::Char('b')]);
+        assert!(editor.save());
+        press(&mut editor, &[Key::Char('\n'), Key::Char('c')]);
+        press(&mut editor, &[Key::Ctrl('z'), Key::Ctrl('z')]);
+        assert_eq!(editor.content(), "ab");
+        assert!(!editor.dirty);
+        press(&mut editor, &[Key::Ctrl('z')]); // Past the save is unsaved again
+        assert!(editor.dirty);
+        press(&mut editor, &[Key::Ctrl('y')]);
+        assert!(!editor.dirty);
+        press(&mut editor, &[Key::Esc, Key::Char('q')]); // Quits straight away
+        assert!(!editor.running);
+        std::fs::remove_file(&path).unwrap();
     }
 }
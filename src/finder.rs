@@ -0,0 +1,73 @@
+// finder.rs
+// Handles fuzzy file finding -- listing files under a directory and scoring them against a query
+
+use std::path::Path;
+
+const IGNORED_DIRECTORIES: [&str; 4] = [".git", "target", "node_modules", ".hg"]; // Never worth descending into
+const MAX_DEPTH: usize = 8; // How many directories deep the walk goes
+
+// Lists files under root as paths relative to it, stopping after limit files
+pub fn list_files(root: &Path, limit: usize) -> Vec<String> {
+    let mut files = vec![];
+    let mut pending = vec![(root.to_path_buf(), 0)]; // Directories still to walk, with their depth
+    while let Some((directory, depth)) = pending.pop() {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue, // Unreadable directories are skipped
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if depth < MAX_DEPTH && !IGNORED_DIRECTORIES.contains(&name.as_str()) {
+                        pending.push((path, depth + 1));
+                    }
+                }
+                Ok(_) => {
+                    if let Ok(relative) = path.strip_prefix(root) {
+                        files.push(relative.to_string_lossy().to_string());
+                    }
+                    if files.len() >= limit {
+                        return files;
+                    }
+                }
+                Err(_) => (), // Entries whose type can't be read are skipped
+            }
+        }
+    }
+    files
+}
+
+// Scores how well candidate matches query, or None if the query's characters don't all appear in order
+// Higher is better -- consecutive matches and matches at the start of a path segment score extra
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut query_chars = query.chars().map(|c| c.to_ascii_lowercase()).peekable();
+    let mut previous_matched = false; // Did the previous candidate character match?
+    let mut previous = '/'; // Previous candidate character, the start counts as a segment boundary
+    for c in candidate.chars() {
+        let wanted = match query_chars.peek() {
+            Some(wanted) => *wanted,
+            None => break, // Whole query matched
+        };
+        if c.to_ascii_lowercase() == wanted {
+            score += 1;
+            if previous_matched { // Run of matching characters
+                score += 5;
+            }
+            if matches!(previous, '/' | '_' | '-' | '.' | ' ') { // Start of a word or path segment
+                score += 10;
+            }
+            query_chars.next();
+            previous_matched = true;
+        } else {
+            previous_matched = false;
+        }
+        previous = c;
+    }
+    if query_chars.peek().is_some() { // Ran out of candidate before matching the whole query
+        return None;
+    }
+    Some(score - candidate.len() as i64 / 10) // Prefer shorter paths on ties
+}
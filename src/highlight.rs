@@ -0,0 +1,156 @@
+// highlight.rs
+// Handles syntax highlighting -- splitting a line into spans of keywords, strings, numbers and comments to color
+
+use std::ops::Range;
+use std::path::Path;
+
+use crate::terminal::Color;
+use crate::Document;
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for", "if",
+    "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct",
+    "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+];
+
+// What a span of text is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Token {
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+impl Token {
+    // Foreground color the token is drawn in
+    pub fn color(self) -> Color {
+        match self {
+            Token::Keyword => Color::Magenta,
+            Token::String => Color::Green,
+            Token::Number => Color::Cyan,
+            Token::Comment => Color::Blue,
+        }
+    }
+}
+
+// A run of a line to color
+#[derive(Debug, PartialEq)]
+pub struct Span {
+    pub columns: Range<usize>, // Character indices the span covers
+    pub token: Token,          // What it is
+}
+
+// What to look for in one file type -- strings and numbers are colored in every file, keywords and comments only in known ones
+pub struct Syntax {
+    keywords: &'static [&'static str],  // Words colored as keywords
+    line_comment: Option<&'static str>, // Marker starting a comment that runs to the end of the line
+    block_comments: bool,               // Are /* */ comments used?
+}
+
+impl Syntax {
+    // Syntax for the document's file type, worked out from its extension
+    pub fn for_document(document: &Document) -> Self {
+        let keywords = match Path::new(&document.path).extension().and_then(|extension| extension.to_str()) {
+            Some("rs") => RUST_KEYWORDS,
+            _ => &[],
+        };
+        let (line_comment, block_comments) = match document.comment_syntax() {
+            Some((marker, block_comments)) => (Some(marker), block_comments),
+            None => (None, false),
+        };
+        Self { keywords, line_comment, block_comments }
+    }
+
+    // Splits line into the spans to color, in order, leaving plain text out
+    // Lines are highlighted on their own, so a /* */ comment spanning lines is only colored from its opening marker to the end of that line
+    pub fn highlight(&self, line: &str) -> Vec<Span> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut spans = vec![];
+        let mut index = 0;
+        while index < chars.len() {
+            let start = index;
+            let token = if self.line_comment.is_some_and(|marker| starts_with(&chars[index..], marker)) {
+                index = chars.len(); // Rest of the line
+                Token::Comment
+            } else if self.block_comments && starts_with(&chars[index..], "/*") {
+                index = (index + 2..chars.len()).find(|end| starts_with(&chars[*end..], "*/")).map_or(chars.len(), |end| end + 2);
+                Token::Comment
+            } else if chars[index] == '"' {
+                index += 1;
+                while index < chars.len() && chars[index] != '"' {
+                    index += if chars[index] == '\\' { 2 } else { 1 }; // Skip escaped quotes
+                }
+                index = (index + 1).min(chars.len()); // Closing quote, if there is one
+                Token::String
+            } else if chars[index].is_ascii_digit() {
+                // Digits with suffixes and hex letters (10u8, 0xFF) and a decimal point only when a digit follows it
+                while index < chars.len()
+                    && (is_word_char(chars[index]) || chars[index] == '.' && chars.get(index + 1).is_some_and(char::is_ascii_digit))
+                {
+                    index += 1;
+                }
+                Token::Number
+            } else if is_word_char(chars[index]) { // A whole word, so digits inside identifiers aren't numbers
+                index += chars[index..].iter().take_while(|c| is_word_char(**c)).count();
+                let word: String = chars[start..index].iter().collect();
+                if !self.keywords.contains(&word.as_str()) {
+                    continue;
+                }
+                Token::Keyword
+            } else {
+                index += 1;
+                continue;
+            };
+            spans.push(Span { columns: start..index, token });
+        }
+        spans
+    }
+}
+
+// Does chars start with text?
+fn starts_with(chars: &[char], text: &str) -> bool {
+    let count = text.chars().count();
+    chars.len() >= count && chars[..count].iter().copied().eq(text.chars())
+}
+
+// Is c part of an identifier or number?
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax(path: &str) -> Syntax {
+        Syntax::for_document(&Document { path: path.to_string(), ..Document::default() })
+    }
+
+    fn span(columns: Range<usize>, token: Token) -> Span {
+        Span { columns, token }
+    }
+
+    #[test]
+    fn rust_keywords_strings_numbers_and_comments() {
+        let spans = syntax("main.rs").highlight("let x1 = \"a \\\" b\" + 0xFF; // done");
+        assert_eq!(spans, vec![
+            span(0..3, Token::Keyword),
+            span(9..17, Token::String),
+            span(20..24, Token::Number),
+            span(26..33, Token::Comment),
+        ]);
+        assert_eq!(syntax("main.rs").highlight("a /* b */ 1.5..2"), vec![
+            span(2..9, Token::Comment),
+            span(10..13, Token::Number),
+            span(15..16, Token::Number),
+        ]);
+    }
+
+    #[test]
+    fn other_files_color_only_strings_and_numbers() {
+        let spans = syntax("notes.txt").highlight("let \"ä\" 42 // x");
+        assert_eq!(spans, vec![span(4..7, Token::String), span(8..10, Token::Number)]);
+        assert_eq!(syntax("run.sh").highlight("\"unterminated # x"), vec![span(0..17, Token::String)]);
+    }
+}
@@ -0,0 +1,169 @@
+// history.rs
+// Handles undo and redo -- a bounded stack of the line ranges each edit replaced
+
+use crate::terminal::Position;
+
+// Where the cursor was, as the screen cursor position and the scroll position
+#[derive(Clone, Copy)]
+pub struct View {
+    pub cursor: Position,
+    pub scroll_position: usize,
+}
+
+// One edit -- lines start..start + removed.len() were replaced by inserted
+pub struct Change {
+    pub start: usize,          // First line the edit touched
+    pub removed: Vec<String>,  // Lines there before the edit
+    pub inserted: Vec<String>, // Lines there after the edit
+    pub before: View,          // View to go back to on undo
+    pub after: View,           // View to go back to on redo
+    pub joined: bool,          // Undone and redone together with the change before it (typing a run of characters)
+}
+
+impl Change {
+    // Puts the lines back as they were before the change
+    pub fn revert(&self, lines: &mut Vec<String>) {
+        lines.splice(self.start..self.start + self.inserted.len(), self.removed.iter().cloned());
+    }
+
+    // Makes the change again
+    pub fn apply(&self, lines: &mut Vec<String>) {
+        lines.splice(self.start..self.start + self.removed.len(), self.inserted.iter().cloned());
+    }
+}
+
+pub struct History {
+    undo: Vec<Change>,    // Changes made, most recent last
+    redo: Vec<Change>,    // Changes undone, most recently undone last
+    saved: Option<usize>, // How many changes were on the undo stack when the document matched its file, None once undo can't get back there
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self { undo: vec![], redo: vec![], saved: Some(0) }
+    }
+}
+
+impl History {
+    // Records a new change, which drops anything that could have been redone
+    // Keeps at most limit changes (each run of typing counting as one), forgetting the oldest
+    pub fn record(&mut self, change: Change, limit: usize) {
+        if self.saved > Some(self.undo.len()) { // The saved state was undone, and can't be redone any more
+            self.saved = None;
+        }
+        self.redo.clear();
+        self.undo.push(change);
+        let groups = self.undo.iter().enumerate().filter(|(index, change)| *index == 0 || !change.joined).count();
+        if groups > limit { // Forget the oldest change, and any joined onto it, never the run still being typed
+            let end = self.undo.iter().skip(1).position(|change| !change.joined).map_or(self.undo.len(), |index| index + 1);
+            self.undo.drain(..end);
+            self.saved = self.saved.and_then(|saved| saved.checked_sub(end));
+        }
+    }
+
+    // Takes the most recent group of changes to undo, most recent first, moving them onto the redo stack
+    pub fn undo(&mut self) -> &[Change] {
+        let count = (self.undo.iter().rev().take_while(|change| change.joined).count() + 1).min(self.undo.len());
+        let start = self.undo.len() - count;
+        self.redo.extend(self.undo.drain(start..).rev());
+        &self.redo[self.redo.len() - count..]
+    }
+
+    // Takes the most recently undone group of changes to redo, oldest first, moving them back onto the undo stack
+    pub fn redo(&mut self) -> &[Change] {
+        let count = match self.redo.last() {
+            Some(_) => 1 + self.redo.iter().rev().skip(1).take_while(|change| change.joined).count(),
+            None => 0,
+        };
+        let start = self.redo.len() - count;
+        self.undo.extend(self.redo.drain(start..).rev());
+        &self.undo[self.undo.len() - count..]
+    }
+
+    // Notes that the document now matches its file
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.undo.len());
+    }
+
+    // Has undo or redo brought the document back to how it was when last saved (or loaded)?
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.undo.len())
+    }
+
+    // Forgets every change, for when a different document is loaded
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.saved = Some(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: usize = 1000;
+
+    fn change(line: &str, joined: bool) -> Change {
+        let view = View { cursor: Position::default(), scroll_position: 0 };
+        Change { start: 0, removed: vec![], inserted: vec![line.to_string()], before: view, after: view, joined }
+    }
+
+    #[test]
+    fn oldest_groups_are_forgotten_past_the_limit() {
+        let mut history = History::default();
+        history.record(change("first", false), LIMIT);
+        history.record(change("first, joined", true), LIMIT);
+        for _ in 0..LIMIT - 1 {
+            history.record(change("later", false), LIMIT);
+        }
+        assert_eq!(history.undo.len(), LIMIT + 1); // Nothing is dropped until the limit is passed
+        history.record(change("last", false), LIMIT);
+        assert_eq!(history.undo.len(), LIMIT); // Both changes of the oldest group go
+        assert!(history.undo.iter().all(|change| change.inserted[0] != "first, joined"));
+        assert_eq!(history.undo().len(), 1);
+        assert_eq!(history.redo()[0].inserted, vec!["last"]);
+    }
+
+    #[test]
+    fn a_run_longer_than_the_limit_is_kept_whole() {
+        let mut history = History::default();
+        history.record(change("earlier", false), LIMIT);
+        history.record(change("typed", false), LIMIT);
+        for _ in 0..LIMIT + 100 {
+            history.record(change("typed", true), LIMIT);
+        }
+        assert_eq!(history.undo().len(), LIMIT + 101); // The whole run comes back in one undo
+        assert_eq!(history.undo()[0].inserted, vec!["earlier"]);
+    }
+
+    #[test]
+    fn saved_state_is_found_again_by_undo_and_redo() {
+        let mut history = History::default();
+        history.record(change("one", false), LIMIT);
+        history.mark_saved();
+        history.record(change("two", false), LIMIT);
+        assert!(!history.is_saved());
+        history.undo();
+        assert!(history.is_saved());
+        history.undo();
+        assert!(!history.is_saved());
+        history.redo();
+        assert!(history.is_saved());
+        history.undo();
+        history.record(change("other", false), LIMIT); // Replaces what was saved, which can't come back
+        history.undo();
+        assert!(!history.is_saved());
+    }
+
+    #[test]
+    fn saved_state_forgotten_past_the_limit_is_never_found() {
+        let mut history = History::default();
+        history.record(change("first", false), 2);
+        history.record(change("second", false), 2);
+        history.record(change("third", false), 2); // Drops first, and the state before it with it
+        history.undo();
+        history.undo();
+        assert!(!history.is_saved());
+    }
+}
@@ -1,28 +1,66 @@
 // main.rs
 // Entry point
 
+mod action;
+mod bench;
+mod config;
 mod document;
 mod editor;
+mod finder;
+mod highlight;
+mod history;
+mod observer;
+mod preview;
+mod recent;
 mod terminal;
 
 use editor::Editor;
+pub use action::Action;
+pub use config::Config;
 pub use document::Document;
 pub use terminal::Terminal;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    // If args are passed, open the first one
-    if args.len() > 1 {
-        let mut editor = Editor::default();
-        editor.open_document = Document::from_file(&args[1]);
+    let mut args: Vec<String> = std::env::args().collect();
+    // Pull out --cwd <dir>, the directory relative paths are resolved against
+    let mut base_directory = None;
+    if let Some(index) = args.iter().position(|arg| arg == "--cwd") {
+        if index + 1 < args.len() {
+            base_directory = Some(std::path::PathBuf::from(args.remove(index + 1)));
+        }
+        args.remove(index);
+    }
+    // Pull out -r (or --read-only), which opens the files for viewing only
+    let read_only = args.iter().any(|arg| arg == "-r" || arg == "--read-only");
+    args.retain(|arg| arg != "-r" && arg != "--read-only");
+    // Hidden benchmark mode, times loading, scrolling and editing a file then exits
+    if let Some(index) = args.iter().position(|arg| arg == "--bench-open") {
+        match args.get(index + 1) {
+            Some(path) => bench::run(path),
+            None => println!("--bench-open needs a file to open"),
+        }
+        return;
+    }
+    // Open the args if any are passed, each in its own buffer, otherwise fall back to the scratch file or the start page
+    let config = Config::load();
+    let files = match args.get(1..) {
+        Some(files) if !files.is_empty() => files.to_vec(),
+        _ if !config.scratch_file.is_empty() => vec![config.scratch_file.clone()],
+        _ => vec![],
+    };
+    if !files.is_empty() || config.start_page_without_file {
+        terminal::restore_on_panic();
+        let mut editor = Editor::with_config(config);
+        editor.base_directory = base_directory;
+        editor.read_only = read_only;
+        let paths: Vec<String> = files.iter().map(|file| editor.resolve_path(file)).collect();
+        editor.open_files(&paths);
         editor.run();
     } else {
         println!(
-            "{}{}{}",
+            "{}Please provide a file to open or create.{}",
             termion::color::Fg(termion::color::Red),
-            "Please provide a file to open or create.",
             termion::color::Fg(termion::color::Reset)
         );
-        return;
     }
 }
@@ -0,0 +1,12 @@
+// observer.rs
+// Handles editor observers -- hooks an embedding application implements to hear about what the editor does
+
+use crate::Document;
+
+// Receives editor events, every method does nothing unless overridden
+pub trait Observer {
+    fn document_changed(&mut self, _document: &Document) {} // The text changed (edits, reloads, filters, opening a file)
+    fn saved(&mut self, _path: &str) {}                      // The document was written to path
+    fn cursor_moved(&mut self, _line: usize, _column: usize) {} // The cursor moved, line is the document line index
+    fn mode_changed(&mut self, _mode: &str) {}               // The mode shown by {mode} changed, e.g. EDIT to OPTIONS
+}
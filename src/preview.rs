@@ -0,0 +1,108 @@
+// preview.rs
+// Handles Markdown previews -- an observer that renders saved Markdown files to ANSI text next to them
+
+use std::path::Path;
+
+use crate::observer::Observer;
+
+const BOLD: &str = "\x1b[1m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const DIM: &str = "\x1b[2m";
+const INVERT: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+// Writes path.preview with the rendered Markdown each time a .md or .markdown file is saved
+// View it from another terminal with something like `less -R file.md.preview`
+pub struct MarkdownPreview;
+
+impl Observer for MarkdownPreview {
+    fn saved(&mut self, path: &str) {
+        let is_markdown = matches!(Path::new(path).extension().and_then(|extension| extension.to_str()), Some("md" | "markdown"));
+        if !is_markdown {
+            return;
+        }
+        // Previews are best effort, a file that can't be read or written just isn't previewed
+        if let Ok(text) = std::fs::read_to_string(path) {
+            let _ = std::fs::write(format!("{}.preview", path), render_markdown(&text));
+        }
+    }
+}
+
+// Renders Markdown as text with ANSI styling -- headings, lists, quotes, code and inline emphasis
+pub fn render_markdown(text: &str) -> String {
+    let mut output = String::new();
+    let mut in_code_block = false; // Between ``` fences?
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") { // Fences themselves aren't shown
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            output.push_str(&format!("    {}{}{}\n", DIM, line, RESET));
+            continue;
+        }
+        let trimmed = line.trim_start();
+        let heading_level = trimmed.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&heading_level) && trimmed[heading_level..].starts_with(' ') {
+            output.push_str(&format!("{}{}{}{}\n", BOLD, UNDERLINE, render_inline(trimmed[heading_level..].trim()), RESET));
+        } else if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|bullet| trimmed.strip_prefix(bullet)) {
+            let indent = &line[..line.len() - trimmed.len()]; // Keep nesting
+            output.push_str(&format!("{}  • {}\n", indent, render_inline(item)));
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            output.push_str(&format!("{}│ {}{}\n", ITALIC, render_inline(quote.trim_start()), RESET));
+        } else {
+            output.push_str(&render_inline(line));
+            output.push('\n');
+        }
+    }
+    output
+}
+
+// Renders **bold**, *italic* / _italic_ and `code` spans within a line
+fn render_inline(text: &str) -> String {
+    let mut output = String::new();
+    let (mut bold, mut italic, mut code) = (false, false, false); // Spans currently open
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => {
+                code = !code;
+                output.push_str(if code { INVERT } else { RESET });
+            }
+            _ if code => output.push(c), // Nothing is markup inside code
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                bold = !bold;
+                output.push_str(if bold { BOLD } else { RESET });
+            }
+            '*' | '_' => {
+                italic = !italic;
+                output.push_str(if italic { ITALIC } else { RESET });
+            }
+            _ => output.push(c),
+        }
+    }
+    if bold || italic || code { // Unclosed span, don't let it leak onto the next line
+        output.push_str(RESET);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_block_and_inline_markup() {
+        let rendered = render_markdown("# Title\n- a **b**\n> quote\n```\nlet x;\n```\nuse `x` *now*");
+        let expected = [
+            format!("{}{}Title{}", BOLD, UNDERLINE, RESET),
+            format!("  • a {}b{}", BOLD, RESET),
+            format!("{}│ quote{}", ITALIC, RESET),
+            format!("    {}let x;{}", DIM, RESET),
+            format!("use {}x{} {}now{}", INVERT, RESET, ITALIC, RESET),
+        ];
+        assert_eq!(rendered, expected.join("\n") + "\n");
+    }
+}
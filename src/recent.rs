@@ -0,0 +1,40 @@
+// recent.rs
+// Handles the recent files list -- remembering opened files across sessions in ~/.bim_recent
+
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT: usize = 10; // How many files are remembered
+
+// Where the list is stored, None if there is no home directory
+fn store_path() -> Option<PathBuf> {
+    std::env::var("HOME").ok().map(|home| Path::new(&home).join(".bim_recent"))
+}
+
+// Loads the recent files, most recent first, leaving out any that no longer exist
+pub fn load() -> Vec<String> {
+    let text = match store_path().and_then(|path| std::fs::read_to_string(path).ok()) {
+        Some(text) => text,
+        None => return vec![], // Nothing remembered yet
+    };
+    text.lines()
+        .filter(|line| !line.is_empty() && Path::new(line).is_file())
+        .take(MAX_RECENT)
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// Writes the recent files back, one path per line
+// Failures are ignored, losing the list is never worth interrupting the user for
+pub fn save(files: &[String]) {
+    if let Some(path) = store_path() {
+        let _ = std::fs::write(path, files.join("\n") + "\n");
+    }
+}
+
+// Moves path to the front of files as an absolute path, dropping the oldest past the limit
+pub fn add(files: &mut Vec<String>, path: &str) {
+    let path = std::path::absolute(path).map(|path| path.to_string_lossy().to_string()).unwrap_or_else(|_| path.to_string());
+    files.retain(|file| *file != path);
+    files.insert(0, path);
+    files.truncate(MAX_RECENT);
+}
@@ -3,75 +3,344 @@
 
 use std::io::stdout;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use termion::input::MouseTerminal;
 use termion::raw::{IntoRawMode, RawTerminal};
 
-#[derive(Clone, Copy)]
+const MOUSE_REPORTING_OFF: &str = "\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l"; // Undoes what MouseTerminal turns on
+
+static RESIZED: AtomicBool = AtomicBool::new(false); // Set by the SIGWINCH handler, cleared once the new size is read
+
+// SIGWINCH handler -- only flags the resize, the size itself is read outside the signal context
+extern "C" fn handle_resize(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+// Waits up to timeout_ms for input on stdin, returning whether any arrived
+pub fn input_pending(timeout_ms: u64) -> bool {
+    let mut stdin = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut stdin, 1, timeout_ms.min(i32::MAX as u64) as libc::c_int) > 0 }
+}
+
+// Makes a panic put the terminal back before its message prints -- out of raw mode, with default colors and a visible cursor
+// on a cleared screen -- so a crash leaves a usable shell rather than one needing `reset`
+// Call before raw mode starts, the terminal settings in use then are the ones a panic goes back to
+pub fn restore_on_panic() {
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    let saved = unsafe { libc::tcgetattr(libc::STDOUT_FILENO, &mut original) } == 0;
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if saved {
+            unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &original) };
+        }
+        let mut stdout = stdout();
+        let _ = write!(
+            stdout,
+            "{}\x1b[r\x1b[?1004l{}{}{}{}", // Attributes, scroll region, focus and mouse reporting, like Terminal::restore
+            termion::style::Reset,
+            MOUSE_REPORTING_OFF,
+            termion::cursor::Show,
+            termion::clear::All,
+            termion::cursor::Goto(1, 1)
+        );
+        let _ = stdout.flush();
+        default_hook(info); // The usual message and backtrace
+    }));
+}
+
+// Stdin read straight from the file descriptor with no buffering, so input_pending sees every byte not yet read
+// (std's Stdin buffers ahead, hiding keys that have already arrived from poll)
+pub struct RawStdin;
+
+impl std::io::Read for RawStdin {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let count = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if count >= 0 {
+                return Ok(count as usize);
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted { // Retry reads cut short by a signal (SIGWINCH)
+                return Err(error);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
 pub struct Position {
     pub x: usize, // X position
     pub y: usize, // Y position
 }
 
-impl Default for Position {
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Color {
+    Reset, // The terminal's own default
+    White,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+}
+
+// The terminal operations bim draws with, so the library behind them can be swapped out
+pub trait Backend {
+    fn size(&self) -> Option<(usize, usize)>;              // Width and height in cells, if they can be read
+    fn clear(&mut self);                                   // Clears the whole screen
+    fn clear_line(&mut self);                              // Clears the row the cursor is on
+    fn goto(&mut self, x: usize, y: usize);                // Moves the cursor, 0-based
+    fn write(&mut self, text: &str);                       // Writes text at the cursor
+    fn set_foreground(&mut self, color: Color);            // Sets the color of text written after this
+    fn set_background(&mut self, color: Color);            // Sets the background of text written after this
+    fn set_inverted(&mut self, inverted: bool);            // Swaps foreground and background of text written after this
+    fn set_cursor_visibility(&mut self, visible: bool);    // Shows or hides the cursor
+    fn set_focus_reporting(&mut self, _enabled: bool) {}   // Reports focus changes as input, where supported
+    fn reset_state(&mut self) {}                           // Resets any other state the backend may have changed
+    fn flush(&mut self) -> Result<(), std::io::Error>;     // Sends everything written to the terminal
+}
+
+// Backend writing termion escape sequences to stdout in raw mode, with mouse clicks reported as input
+pub struct TermionBackend {
+    stdout: MouseTerminal<RawTerminal<std::io::Stdout>>, // Raw terminal output
+}
+
+impl Default for TermionBackend {
     fn default() -> Self {
-        Self { x: 0, y: 0 }
+        Self {
+            stdout: MouseTerminal::from(stdout().into_raw_mode().unwrap()),
+        }
+    }
+}
+
+#[allow(unused_must_use)]
+impl Backend for TermionBackend {
+    fn size(&self) -> Option<(usize, usize)> {
+        termion::terminal_size().ok().map(|(width, height)| (width as usize, height as usize))
+    }
+
+    fn clear(&mut self) {
+        write!(self.stdout, "{}", termion::clear::All);
+    }
+
+    fn clear_line(&mut self) {
+        write!(self.stdout, "{}", termion::clear::CurrentLine);
+    }
+
+    fn goto(&mut self, x: usize, y: usize) {
+        write!(self.stdout, "{}", termion::cursor::Goto(x.saturating_add(1) as u16, y.saturating_add(1) as u16));
+    }
+
+    fn write(&mut self, text: &str) {
+        write!(self.stdout, "{}", text);
+    }
+
+    fn set_foreground(&mut self, color: Color) {
+        match color {
+            Color::Reset => write!(self.stdout, "{}", termion::color::Fg(termion::color::Reset)),
+            Color::White => write!(self.stdout, "{}", termion::color::Fg(termion::color::White)),
+            Color::Red => write!(self.stdout, "{}", termion::color::Fg(termion::color::Red)),
+            Color::Green => write!(self.stdout, "{}", termion::color::Fg(termion::color::Green)),
+            Color::Yellow => write!(self.stdout, "{}", termion::color::Fg(termion::color::Yellow)),
+            Color::Blue => write!(self.stdout, "{}", termion::color::Fg(termion::color::Blue)),
+            Color::Magenta => write!(self.stdout, "{}", termion::color::Fg(termion::color::Magenta)),
+            Color::Cyan => write!(self.stdout, "{}", termion::color::Fg(termion::color::Cyan)),
+        };
+    }
+
+    fn set_background(&mut self, color: Color) {
+        match color {
+            Color::Reset => write!(self.stdout, "{}", termion::color::Bg(termion::color::Reset)),
+            Color::White => write!(self.stdout, "{}", termion::color::Bg(termion::color::White)),
+            Color::Red => write!(self.stdout, "{}", termion::color::Bg(termion::color::Red)),
+            Color::Green => write!(self.stdout, "{}", termion::color::Bg(termion::color::Green)),
+            Color::Yellow => write!(self.stdout, "{}", termion::color::Bg(termion::color::Yellow)),
+            Color::Blue => write!(self.stdout, "{}", termion::color::Bg(termion::color::Blue)),
+            Color::Magenta => write!(self.stdout, "{}", termion::color::Bg(termion::color::Magenta)),
+            Color::Cyan => write!(self.stdout, "{}", termion::color::Bg(termion::color::Cyan)),
+        };
+    }
+
+    fn set_inverted(&mut self, inverted: bool) {
+        if inverted {
+            write!(self.stdout, "{}", termion::style::Invert);
+        } else {
+            write!(self.stdout, "{}", termion::style::NoInvert);
+        }
+    }
+
+    fn set_cursor_visibility(&mut self, visible: bool) {
+        if visible {
+            write!(self.stdout, "{}", termion::cursor::Show);
+        } else {
+            write!(self.stdout, "{}", termion::cursor::Hide);
+        }
+    }
+
+    fn set_focus_reporting(&mut self, enabled: bool) {
+        if enabled {
+            write!(self.stdout, "\x1b[?1004h");
+        } else {
+            write!(self.stdout, "\x1b[?1004l");
+        }
+    }
+
+    fn reset_state(&mut self) {
+        write!(self.stdout, "{}\x1b[r{}", termion::style::Reset, MOUSE_REPORTING_OFF); // All text attributes, the scroll region and mouse reporting
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.stdout.flush()
+    }
+}
+
+// Backend that draws nothing, for running the editor without a terminal (benchmarks, embedding)
+pub struct HeadlessBackend {
+    pub width: usize,  // Pretend width in cells
+    pub height: usize, // Pretend height in cells
+}
+
+impl Backend for HeadlessBackend {
+    fn size(&self) -> Option<(usize, usize)> {
+        Some((self.width, self.height))
+    }
+    fn clear(&mut self) {}
+    fn clear_line(&mut self) {}
+    fn goto(&mut self, _x: usize, _y: usize) {}
+    fn write(&mut self, _text: &str) {}
+    fn set_foreground(&mut self, _color: Color) {}
+    fn set_background(&mut self, _color: Color) {}
+    fn set_inverted(&mut self, _inverted: bool) {}
+    fn set_cursor_visibility(&mut self, _visible: bool) {}
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
     }
 }
 
 pub struct Terminal {
-    pub stdout: RawTerminal<std::io::Stdout>, // Raw terminal output
+    backend: Box<dyn Backend>,     // What actually draws to the screen
     pub cursor_position: Position, // Cursor position
-    pub height: usize, // Terminal height in rows
-    pub width: usize, // Terminal width in columns
+    pub height: usize,             // Terminal height in rows
+    pub width: usize,              // Terminal width in columns
+    pub x_offset: usize,           // Columns reserved on the left (gutter), added to the cursor's on-screen x
 }
 
 impl Default for Terminal {
     fn default() -> Self {
+        Self::new(Box::new(TermionBackend::default()))
+    }
+}
+
+impl Terminal {
+    // Creates a terminal drawing through backend
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        let (width, height) = backend.size().expect("Could not read terminal size");
         Self {
-            stdout: stdout().into_raw_mode().unwrap(),
+            backend,
             cursor_position: Position::default(),
-            height: termion::terminal_size().unwrap().1 as usize,
-            width: termion::terminal_size().unwrap().0 as usize,
+            height,
+            width,
+            x_offset: 0,
         }
     }
-}
 
-#[allow(unused_must_use)]
-impl Terminal {
+    // Installs the SIGWINCH handler so resizes can be picked up with refresh_size
+    pub fn watch_resize(&self) {
+        unsafe {
+            libc::signal(libc::SIGWINCH, handle_resize as *const () as libc::sighandler_t);
+        }
+    }
+
+    // Re-reads the terminal size if a resize was signalled, returning whether it changed
+    pub fn refresh_size(&mut self) -> bool {
+        if !RESIZED.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        if let Some((width, height)) = self.backend.size() {
+            self.width = width;
+            self.height = height;
+        }
+        true
+    }
+
+    // Puts the terminal back the way it was found -- default colors, visible cursor and no focus or mouse reporting
+    // Safe to call more than once, it's also run on drop so the terminal is restored however bim exits
+    pub fn restore(&mut self) {
+        self.backend.set_foreground(Color::Reset);
+        self.backend.set_background(Color::Reset);
+        self.backend.set_inverted(false);
+        self.backend.set_cursor_visibility(true);
+        self.backend.set_focus_reporting(false);
+        self.backend.reset_state();
+        let _ = self.backend.flush();
+    }
+
     // Sets the cursor visibility
     pub fn set_cursor_visibility(&mut self, visible: bool) {
-        if visible == true {
-            print!("{}", termion::cursor::Show);
-        } else {
-            print!("{}", termion::cursor::Hide);
-        }
+        self.backend.set_cursor_visibility(visible);
+    }
+
+    // Turns terminal focus reporting on or off (focus changes then arrive as input escape sequences)
+    pub fn set_focus_reporting(&mut self, enabled: bool) {
+        self.backend.set_focus_reporting(enabled);
     }
 
     // Sets the cursor position
     pub fn set_cursor_position(&mut self, position: Position) {
-        let x = position.x.saturating_add(1);
-        let y = position.y.saturating_add(1);
-        write!(self.stdout, "{}", termion::cursor::Goto(x as u16, y as u16));
+        self.backend.goto(position.x.saturating_add(self.x_offset), position.y);
         self.cursor_position = position;
     }
 
-    // Sets the cursor position to 0, 0 without updaing position field
-    pub fn zero_cursor_position(&mut self) {
-        write!(self.stdout, "{}", termion::cursor::Goto(1, 1));
+    // Moves the cursor to a screen cell without updating the position field (ignores the gutter)
+    pub fn goto(&mut self, x: usize, y: usize) {
+        self.backend.goto(x, y);
     }
 
     // Clears the terminal
     pub fn clear(&mut self) {
-        write!(self.stdout, "{}", termion::clear::All);
+        self.backend.clear();
+    }
+
+    // Clears the row the cursor is on
+    pub fn clear_line(&mut self) {
+        self.backend.clear_line();
+    }
+
+    // Writes text at the cursor
+    pub fn write(&mut self, text: &str) {
+        self.backend.write(text);
+    }
+
+    // Sets the text color for everything written after this
+    pub fn set_foreground(&mut self, color: Color) {
+        self.backend.set_foreground(color);
+    }
+
+    // Sets the background color for everything written after this
+    pub fn set_background(&mut self, color: Color) {
+        self.backend.set_background(color);
+    }
+
+    // Swaps foreground and background for everything written after this
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.backend.set_inverted(inverted);
     }
 
     // Returns a copy of the cursor position
     pub fn get_cursor_position(&self) -> Position {
-        self.cursor_position.clone()
+        self.cursor_position
     }
 
     // Flushes the terminal
     pub fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.stdout.flush()
+        self.backend.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
     }
 }